@@ -42,7 +42,7 @@ fn set_bench(c: &mut Criterion) {
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_bench");
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("kvs_{i}"), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let store = KvStore::open(temp_dir.path()).unwrap();
@@ -60,7 +60,7 @@ fn get_bench(c: &mut Criterion) {
         });
     }
 
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{i}"), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let store = SledKvsEngine::open(temp_dir.path()).unwrap();
@@ -0,0 +1,124 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::process::exit;
+use trash_db::engines::kvstore::{
+    inspect, is_store, repair, stats, CompactionTrigger, Damage, KvStore, KvStoreOptions,
+    StoreStats,
+};
+use trash_db::Result;
+
+/// Looks into and fixes the data directory of a kvs store while no server
+/// has it open.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Data directory of the store
+    #[arg(long, default_value = ".", global = true)]
+    dir: PathBuf,
+    /// Base name of the data files
+    #[arg(long, value_name = "NAME", global = true)]
+    file_name: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List every record with its segment and offset
+    Dump,
+    /// Print live and stale bytes as the store accounts for them
+    Stats,
+    /// Check every record's checksum, failing if any segment is damaged
+    Verify,
+    /// Cut damaged segments back to their last readable record
+    Repair,
+    /// Rewrite the live records into fresh segments
+    Compact,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut options = KvStoreOptions::new();
+    if let Some(name) = &cli.file_name {
+        options = options.file_name(name.as_str());
+    }
+    match cli.command {
+        Command::Dump => {
+            println!("gen\toffset\tlen\tkind\tkey\tvalue_len\texpires_at");
+            let damage = inspect(&cli.dir, &options, |record| {
+                let kind = match (record.value_len, record.in_batch) {
+                    (Some(_), false) => "set",
+                    (None, false) => "rm",
+                    (Some(_), true) => "batch:set",
+                    (None, true) => "batch:rm",
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    record.gen,
+                    record.offset,
+                    record.len,
+                    kind,
+                    record.key.escape_ascii(),
+                    record
+                        .value_len
+                        .map_or_else(|| "-".to_owned(), |len| len.to_string()),
+                    record.expires_at
+                );
+            })?;
+            report(&damage);
+        }
+        Command::Stats => print_stats(&stats(&cli.dir, &options)?),
+        Command::Verify => {
+            let mut records = 0;
+            let damage = inspect(&cli.dir, &options, |_| records += 1)?;
+            report(&damage);
+            if !damage.is_empty() {
+                eprintln!("{} damaged segments", damage.len());
+                exit(1);
+            }
+            println!("{} records OK", records);
+        }
+        Command::Repair => {
+            let damage = repair(&cli.dir, &options)?;
+            report(&damage);
+            println!(
+                "Cut {} bytes from {} segments",
+                damage.iter().map(|d| d.len).sum::<u64>(),
+                damage.len()
+            );
+        }
+        Command::Compact => {
+            let store = open(&cli, options)?;
+            store.compact()?;
+            print_stats(&store.stats()?);
+        }
+    }
+    Ok(())
+}
+
+/// Opens the store without letting it start a compaction of its own. Fails
+/// rather than creating a store in a mistyped directory.
+fn open(cli: &Cli, options: KvStoreOptions) -> Result<KvStore> {
+    if !is_store(&cli.dir, &options)? {
+        return Err(From::from(format!("{} holds no store", cli.dir.display())));
+    }
+    options
+        .compaction(CompactionTrigger::StaleBytes(u64::MAX))
+        .open(&cli.dir)
+}
+
+fn report(damage: &[Damage]) {
+    for d in damage {
+        eprintln!(
+            "Segment {} is unreadable from offset {} ({} bytes)",
+            d.gen, d.offset, d.len
+        );
+    }
+}
+
+fn print_stats(stats: &StoreStats) {
+    println!("keys\t{}", stats.keys);
+    println!("segments\t{}", stats.segments);
+    println!("live_bytes\t{}", stats.live_bytes);
+    println!("stale_bytes\t{}", stats.stale_bytes);
+}
@@ -1,11 +1,14 @@
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::PathBuf;
 use std::process::exit;
-use std::str::from_utf8;
-use trash_db::commands::{KvsCommands, KvsResponse};
-use trash_db::{Result, MESSAGE_SIZE};
+use trash_db::client::KvClient;
+use trash_db::codec::Encoding;
+use trash_db::commands::{KvsCommands, KvsResponse, ServerInfo, TxnOp};
+use trash_db::engines::WriteBatch;
+use trash_db::{KvError, Result};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -15,72 +18,287 @@ pub struct Cli {
 
     #[arg(long, default_value_t = format!("127.0.0.1:4000"), global=true)]
     addr: String,
+
+    /// Talk JSON to the server instead of bincode, for debugging
+    #[arg(long, global = true)]
+    json: bool,
 }
 
 #[derive(Subcommand, Serialize, Deserialize, Clone, Debug)]
 pub enum Commands {
     Get {
         #[clap(value_parser)]
-        key: String,
+        key: OsString,
+        /// Read inside the transaction ID
+        #[arg(long, value_name = "ID")]
+        txn: Option<u64>,
     },
     Set {
         #[clap(value_parser)]
-        key: String,
+        key: OsString,
         #[clap(value_parser)]
-        value: String,
+        value: OsString,
+        /// Remove the key after this many seconds
+        #[arg(long, value_name = "SECONDS", conflicts_with = "txn")]
+        ttl: Option<u64>,
+        /// Write inside the transaction ID, taking effect on commit
+        #[arg(long, value_name = "ID")]
+        txn: Option<u64>,
     },
     Rm {
         #[clap(value_parser)]
-        key: String,
+        key: OsString,
+        /// Remove inside the transaction ID, taking effect on commit
+        #[arg(long, value_name = "ID")]
+        txn: Option<u64>,
+    },
+    /// Start a transaction and print its ID
+    Begin,
+    /// Apply the writes of a transaction, failing if a key it read has changed
+    Commit { id: u64 },
+    /// Drop a transaction and its writes
+    Abort { id: u64 },
+    /// Remove an existing key after SECONDS
+    Expire { key: OsString, seconds: u64 },
+    /// Print the seconds left until a key expires, or `none`
+    Ttl { key: OsString },
+    /// Apply several changes at once, given as `set KEY VALUE` and `rm KEY`
+    Batch {
+        #[arg(required = true, num_args = 1..)]
+        ops: Vec<OsString>,
+    },
+    /// Replace the value of KEY only if it currently is EXPECTED
+    Cas {
+        key: OsString,
+        /// Value KEY must have; leave out to require KEY to be missing
+        #[arg(long)]
+        expected: Option<OsString>,
+        /// Value to store; leave out to remove KEY
+        #[arg(long)]
+        new: Option<OsString>,
+    },
+    /// Set KEY only if it does not exist yet
+    SetIfAbsent { key: OsString, value: OsString },
+    /// Set KEY only if it already exists
+    SetIfPresent { key: OsString, value: OsString },
+    /// Add to the integer at KEY and print the result
+    Incr {
+        key: OsString,
+        #[arg(long, default_value_t = 1, allow_negative_numbers = true)]
+        by: i64,
+    },
+    /// Subtract from the integer at KEY and print the result
+    Decr {
+        key: OsString,
+        #[arg(long, default_value_t = 1, allow_negative_numbers = true)]
+        by: i64,
+    },
+    /// Append VALUE to the value of KEY and print the new length
+    Append { key: OsString, value: OsString },
+    /// Have the server write a backup into DEST, a new directory
+    /// inside its data directory
+    Backup { dest: PathBuf },
+    /// Print the versions, engine and features of the server
+    Info,
+    /// List entries in key order, one `key<TAB>value` per line
+    Scan {
+        #[command(subcommand)]
+        scan: Scan,
     },
 }
 
+#[derive(Subcommand, Serialize, Deserialize, Clone, Debug)]
+pub enum Scan {
+    /// Entries with keys from START (inclusive) up to END (exclusive)
+    Range {
+        start: Option<OsString>,
+        end: Option<OsString>,
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Entries whose key starts with PREFIX
+    Prefix { prefix: OsString },
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let addr = cli.addr;
-    let mut stream = TcpStream::connect(addr)?;
-
-    let content = match &cli.command {
-        Commands::Get { key } => serde_json::to_string(&KvsCommands::Get {
-            key: key.to_string(),
-        })
-        .unwrap(),
-        Commands::Set { key, value } => serde_json::to_string(&KvsCommands::Set {
-            key: key.to_string(),
-            value: value.to_string(),
-        })
-        .unwrap(),
-        Commands::Rm { key } => serde_json::to_string(&KvsCommands::Rm {
-            key: key.to_string(),
-        })
-        .unwrap(),
+    let encoding = if cli.json {
+        Encoding::Json
+    } else {
+        Encoding::Bincode
+    };
+    let connect = || KvClient::connect_with_encoding(&cli.addr, encoding);
+    let request = match &cli.command {
+        Commands::Info => return print_info(connect()?.server()),
+        Commands::Get { key, txn: None } => KvsCommands::Get {
+            key: key.as_encoded_bytes().to_vec(),
+        },
+        Commands::Get { key, txn: Some(id) } => KvsCommands::Txn {
+            id: *id,
+            op: TxnOp::Get {
+                key: key.as_encoded_bytes().to_vec(),
+            },
+        },
+        Commands::Set {
+            key,
+            value,
+            ttl,
+            txn: None,
+        } => KvsCommands::Set {
+            key: key.as_encoded_bytes().to_vec(),
+            value: value.as_encoded_bytes().to_vec(),
+            ttl_ms: ttl.map(|ttl| ttl.saturating_mul(1000)),
+        },
+        Commands::Set {
+            key,
+            value,
+            txn: Some(id),
+            ..
+        } => KvsCommands::Txn {
+            id: *id,
+            op: TxnOp::Set {
+                key: key.as_encoded_bytes().to_vec(),
+                value: value.as_encoded_bytes().to_vec(),
+            },
+        },
+        Commands::Expire { key, seconds } => KvsCommands::Expire {
+            key: key.as_encoded_bytes().to_vec(),
+            ttl_ms: seconds.saturating_mul(1000),
+        },
+        Commands::Ttl { key } => KvsCommands::Ttl {
+            key: key.as_encoded_bytes().to_vec(),
+        },
+        Commands::Rm { key, txn: None } => KvsCommands::Rm {
+            key: key.as_encoded_bytes().to_vec(),
+        },
+        Commands::Rm { key, txn: Some(id) } => KvsCommands::Txn {
+            id: *id,
+            op: TxnOp::Rm {
+                key: key.as_encoded_bytes().to_vec(),
+            },
+        },
+        Commands::Begin => KvsCommands::Begin,
+        Commands::Commit { id } => KvsCommands::Commit { id: *id },
+        Commands::Abort { id } => KvsCommands::Abort { id: *id },
+        Commands::Batch { ops } => KvsCommands::Batch(parse_batch(ops)?),
+        Commands::Cas { key, expected, new } => KvsCommands::Cas {
+            key: key.as_encoded_bytes().to_vec(),
+            expected: expected
+                .as_ref()
+                .map(|value| value.as_encoded_bytes().to_vec()),
+            new: new.as_ref().map(|value| value.as_encoded_bytes().to_vec()),
+        },
+        Commands::SetIfAbsent { key, value } => KvsCommands::SetIfAbsent {
+            key: key.as_encoded_bytes().to_vec(),
+            value: value.as_encoded_bytes().to_vec(),
+        },
+        Commands::SetIfPresent { key, value } => KvsCommands::SetIfPresent {
+            key: key.as_encoded_bytes().to_vec(),
+            value: value.as_encoded_bytes().to_vec(),
+        },
+        Commands::Incr { key, by } => KvsCommands::Incr {
+            key: key.as_encoded_bytes().to_vec(),
+            by: *by,
+        },
+        Commands::Decr { key, by } => KvsCommands::Decr {
+            key: key.as_encoded_bytes().to_vec(),
+            by: *by,
+        },
+        Commands::Append { key, value } => KvsCommands::Append {
+            key: key.as_encoded_bytes().to_vec(),
+            value: value.as_encoded_bytes().to_vec(),
+        },
+        Commands::Backup { dest } => KvsCommands::Backup { dest: dest.clone() },
+        Commands::Scan {
+            scan: Scan::Range { start, end, limit },
+        } => KvsCommands::Scan {
+            start: start
+                .as_ref()
+                .map(|start| start.as_encoded_bytes().to_vec()),
+            end: end.as_ref().map(|end| end.as_encoded_bytes().to_vec()),
+            limit: *limit,
+        },
+        Commands::Scan {
+            scan: Scan::Prefix { prefix },
+        } => KvsCommands::ScanPrefix {
+            prefix: prefix.as_encoded_bytes().to_vec(),
+        },
     };
-    stream.write_all(content.as_str().as_bytes()).unwrap();
-    stream.flush().unwrap();
-    let mut buffer = vec![];
-    let mut bytes = [0; MESSAGE_SIZE];
-    loop {
-        let bytes_read = stream.read(&mut bytes).unwrap();
-        buffer.extend_from_slice(&bytes);
-        if bytes_read < MESSAGE_SIZE {
-            break;
+    let res = match connect()?.request(&request) {
+        Ok(res) => res,
+        // a missing key is what `get` found, not a failure
+        Err(e) if is_not_found(&*e) && matches!(cli.command, Commands::Get { .. }) => {
+            println!("{}", e);
+            return Ok(());
         }
-    }
-    let res = from_utf8(&mut buffer).unwrap().trim_matches(char::from(0));
-    let res: KvsResponse = serde_json::from_str(res)?;
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
     match res {
         KvsResponse::Ok(Some(res)) => {
-            println!("{}", res);
+            // values are printed as they are, whether or not they are text
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&res)?;
+            stdout.write_all(b"\n")?;
         }
         KvsResponse::Ok(None) => {}
-        KvsResponse::Err(e) => {
-            if let Commands::Get { key: _ } = &cli.command {
-                println!("{}", e);
-            } else {
-                eprintln!("{}", e);
-                exit(1);
+        KvsResponse::Ttl(Some(ttl_ms)) => println!("{}", ttl_ms.div_ceil(1000)),
+        KvsResponse::Ttl(None) => println!("none"),
+        KvsResponse::Written(true) => {}
+        KvsResponse::Written(false) => {
+            let reason = match &cli.command {
+                Commands::SetIfAbsent { .. } => "Key already exists",
+                Commands::SetIfPresent { .. } => "Key not found",
+                _ => "Value did not match",
+            };
+            eprintln!("{}", reason);
+            exit(1);
+        }
+        KvsResponse::Integer(n) => println!("{}", n),
+        KvsResponse::Transaction(id) => println!("{}", id),
+        KvsResponse::Entries(entries) => {
+            let mut stdout = io::stdout().lock();
+            for (key, value) in entries {
+                stdout.write_all(&key)?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
             }
         }
+        KvsResponse::Err { .. } => unreachable!("failed requests come back as errors"),
     };
     Ok(())
 }
+
+fn is_not_found(e: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(e.downcast_ref::<KvError>(), Some(KvError::KeyNotFound))
+}
+
+fn print_info(server: &ServerInfo) -> Result<()> {
+    println!("protocol_version\t{}", server.protocol_version);
+    println!("server_version\t{}", server.server_version);
+    println!("engine\t{}", server.engine);
+    println!("features\t{}", server.features.join(","));
+    Ok(())
+}
+
+fn parse_batch(args: &[OsString]) -> Result<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    let mut args = args.iter().map(|arg| arg.as_encoded_bytes().to_vec());
+    while let Some(op) = args.next() {
+        let mut operand = || args.next().ok_or("Missing operand in batch");
+        match op.as_slice() {
+            b"set" => batch.set(operand()?, operand()?),
+            b"rm" => batch.remove(operand()?),
+            _ => {
+                return Err(From::from(format!(
+                    "Unknown batch operation {}",
+                    String::from_utf8_lossy(&op)
+                )))
+            }
+        };
+    }
+    Ok(batch)
+}
@@ -1,14 +1,23 @@
 use std::{
+    env,
     error::Error,
-    fs::{self, OpenOptions},
+    fs::{self, File, OpenOptions},
     io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use log::info;
 use serde::{Deserialize, Serialize};
 use trash_db::{
-    engines::{kvstore::KvStore, sled::SledKvsEngine, KvsEngine},
+    engines::{
+        export, import,
+        kvstore::{remove_store, CompactionTrigger, KvStore, KvStoreOptions},
+        migrate,
+        sled::{self, SledKvsEngine},
+        Format, KvsEngine, SyncPolicy,
+    },
     server::KvServer,
     thread_pool::{rayon::RayonThreadPool, ThreadPool},
     Result,
@@ -17,42 +26,284 @@ use trash_db::{
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(long, default_value_t = format!("127.0.0.1:4000"))]
     addr: String,
     #[arg(value_enum, long)]
     engine: Option<Engine>,
+    /// Compact once this many bytes of the log are stale (kvs engine)
+    #[arg(long, value_name = "BYTES", conflicts_with = "compaction_ratio")]
+    compaction_threshold: Option<u64>,
+    /// Compact once this fraction of the log is stale (kvs engine)
+    #[arg(long, value_name = "RATIO")]
+    compaction_ratio: Option<f64>,
+    /// Start a new segment once the active one reaches this size (kvs engine)
+    #[arg(long, value_name = "BYTES")]
+    segment_size: Option<u64>,
+    /// Base name of the data files (kvs engine)
+    #[arg(long, value_name = "NAME")]
+    file_name: Option<String>,
+    /// Buffer size for reading data files (kvs engine)
+    #[arg(long, value_name = "BYTES")]
+    read_buffer_size: Option<usize>,
+    /// Buffer size for appending to data files (kvs engine)
+    #[arg(long, value_name = "BYTES")]
+    write_buffer_size: Option<usize>,
+    /// When writes are synced to disk; defaults to never for kvs and always for sled
+    #[arg(value_enum, long)]
+    sync: Option<Sync>,
+    /// Milliseconds between syncs with `--sync interval`
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    sync_interval: u64,
+    /// Close connections that send no request for this many seconds. Each open
+    /// connection holds one of the server's threads, one per CPU; clients beyond
+    /// that are turned away as busy
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    idle_timeout: u64,
+}
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rebuild the data in the current directory from a backup, with the engine that wrote it, then exit
+    Restore { backup: PathBuf },
+    /// Copy the data of the current engine into another one and switch to it, then exit.
+    /// The old engine's files stay unless --remove-old is given, and while they do the
+    /// data cannot be migrated back
+    Migrate {
+        #[arg(value_enum, long)]
+        to: Engine,
+        /// Delete the old engine's files once the new one is in use
+        #[arg(long)]
+        remove_old: bool,
+    },
+    /// Write every key to FILE, or to stdout, then exit
+    Export {
+        file: Option<PathBuf>,
+        #[arg(value_enum, long, default_value_t = FileFormat::Jsonl)]
+        format: FileFormat,
+    },
+    /// Load the keys in FILE, or in stdin, then exit
+    Import {
+        file: Option<PathBuf>,
+        #[arg(value_enum, long, default_value_t = FileFormat::Jsonl)]
+        format: FileFormat,
+    },
 }
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
 enum Engine {
     Kvs,
     Sled,
 }
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum FileFormat {
+    Jsonl,
+    Csv,
+}
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Sync {
+    Never,
+    Always,
+    Group,
+    Interval,
+}
 
 fn main() -> std::result::Result<(), Box<dyn Error>> {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .init();
     let cli = Cli::parse();
+    if let Some(Command::Migrate { to, remove_old }) = cli.command {
+        return migrate_to(&cli, to, remove_old);
+    }
+    if let Some(Command::Restore { backup }) = &cli.command {
+        return restore(&cli, backup);
+    }
     let selection_engine = cli.engine;
     let current_engine = get_current_engine()?;
     let engine = handle_engine_selection(current_engine, selection_engine)?;
-    let addr = cli.addr;
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {:?}", engine);
-    let pool = RayonThreadPool::new(num_cpus::get())?;
+    if let Some(command @ (Command::Export { .. } | Command::Import { .. })) = &cli.command {
+        let path = env::current_dir()?;
+        return match engine {
+            Engine::Kvs => transfer(
+                &KvStore::open_with_options(&path, kvstore_options(&cli))?,
+                command,
+            ),
+            Engine::Sled => transfer(
+                &SledKvsEngine::open_with_sync(
+                    &path,
+                    sync_policy(&cli).unwrap_or(SyncPolicy::Always),
+                )?,
+                command,
+            ),
+        };
+    }
+    let threads = num_cpus::get();
+    let pool = RayonThreadPool::new(threads)?;
     match engine {
-        Engine::Kvs => run_with_engine(KvStore::default(), pool, &addr),
-        Engine::Sled => run_with_engine(SledKvsEngine::default(), pool, &addr),
+        Engine::Kvs => {
+            let store = KvStore::open_with_options(&env::current_dir()?, kvstore_options(&cli))?;
+            run_with_engine(store, pool, threads, &cli)
+        }
+        Engine::Sled => {
+            let sync = sync_policy(&cli).unwrap_or(SyncPolicy::Always);
+            let engine = SledKvsEngine::open_with_sync(env::current_dir()?, sync)?;
+            run_with_engine(engine, pool, threads, &cli)
+        }
     }
 }
 
-fn run_with_engine<E: KvsEngine, T: ThreadPool>(
+fn run_with_engine<E: KvsEngine, T: ThreadPool + Send + std::marker::Sync + 'static>(
     engine: E,
     pool: T,
-    addr: &str,
+    threads: usize,
+    cli: &Cli,
 ) -> crate::Result<()> {
-    let mut server = KvServer::new(engine, pool);
-    server.run(addr)
+    let mut server = KvServer::new(engine, pool)
+        .idle_timeout(Duration::from_secs(cli.idle_timeout))
+        .max_connections(threads)
+        .data_dir(env::current_dir()?);
+    server.run(&cli.addr)
+}
+
+/// Rebuilds the data in the current directory from `backup` and makes the
+/// engine that wrote it the current one.
+fn restore(cli: &Cli, backup: &Path) -> Result<()> {
+    let engine = if sled::is_database(backup) {
+        Engine::Sled
+    } else {
+        Engine::Kvs
+    };
+    if let Some(selected) = cli.engine.or(get_current_engine()?) {
+        if selected != engine {
+            return Err(From::from(format!(
+                "{} holds a {:?} backup, not {:?}",
+                backup.display(),
+                engine,
+                selected
+            )));
+        }
+    }
+    info!("Restoring {:?} backup from {}", engine, backup.display());
+    let path = env::current_dir()?;
+    match engine {
+        Engine::Kvs => KvStore::restore_with_options(backup, &path, kvstore_options(cli))?,
+        Engine::Sled => SledKvsEngine::restore(backup, &path)?,
+    }
+    write_engine(engine)
+}
+
+/// Moves the data over to the engine `to` and makes it the current one.
+/// The old engine's files are left in place unless `remove_old` is set.
+fn migrate_to(cli: &Cli, to: Engine, remove_old: bool) -> Result<()> {
+    let from = get_current_engine()?.unwrap_or(Engine::Kvs);
+    if from == to {
+        return Err(From::from(format!("Already using the {:?} engine", to)));
+    }
+    info!("Migrating from {:?} to {:?}", from, to);
+    let path = env::current_dir()?;
+    // every page is synced, so `.engine` only moves on once the copy is on disk
+    let count = match from {
+        Engine::Kvs => migrate_into(
+            &KvStore::open_with_options(&path, kvstore_options(cli))?,
+            &SledKvsEngine::open_with_sync(&path, SyncPolicy::Always)?,
+        )?,
+        Engine::Sled => migrate_into(
+            &SledKvsEngine::open(&path)?,
+            &KvStore::open_with_options(&path, kvstore_options(cli).sync(SyncPolicy::Always))?,
+        )?,
+    };
+    write_engine(to)?;
+    info!("Migrated {} keys", count);
+    if remove_old {
+        info!("Removing the files of the {:?} engine", from);
+        match from {
+            Engine::Kvs => remove_store(&path, &kvstore_options(cli))?,
+            Engine::Sled => SledKvsEngine::remove_database(&path)?,
+        }
+    }
+    Ok(())
+}
+
+fn migrate_into(from: &impl KvsEngine, to: &impl KvsEngine) -> Result<u64> {
+    if !to.scan(.., Some(1))?.is_empty() {
+        return Err(From::from(
+            "The target engine already holds data in this directory",
+        ));
+    }
+    migrate(from, to)
+}
+
+/// Runs an export or import command against `engine`.
+fn transfer(engine: &impl KvsEngine, command: &Command) -> Result<()> {
+    match command {
+        Command::Export { file, format } => {
+            let format = file_format(*format);
+            let count = match file {
+                Some(file) => export(engine, format, File::create(file)?)?,
+                None => export(engine, format, io::stdout().lock())?,
+            };
+            info!("Exported {} keys", count);
+        }
+        Command::Import { file, format } => {
+            let format = file_format(*format);
+            let count = match file {
+                Some(file) => import(engine, format, File::open(file)?)?,
+                None => import(engine, format, io::stdin().lock())?,
+            };
+            info!("Imported {} keys", count);
+        }
+        _ => unreachable!("not an export or import"),
+    }
+    Ok(())
+}
+
+fn file_format(format: FileFormat) -> Format {
+    match format {
+        FileFormat::Jsonl => Format::JsonLines,
+        FileFormat::Csv => Format::Csv,
+    }
+}
+
+fn kvstore_options(cli: &Cli) -> KvStoreOptions {
+    let mut options = KvStoreOptions::new();
+    if let Some(threshold) = cli.compaction_threshold {
+        options = options.compaction(CompactionTrigger::StaleBytes(threshold));
+    }
+    if let Some(ratio) = cli.compaction_ratio {
+        options = options.compaction(CompactionTrigger::StaleRatio(ratio));
+    }
+    if let Some(size) = cli.segment_size {
+        options = options.max_segment_size(size);
+    }
+    if let Some(name) = &cli.file_name {
+        options = options.file_name(name);
+    }
+    if let Some(size) = cli.read_buffer_size {
+        options = options.read_buffer_size(size);
+    }
+    if let Some(size) = cli.write_buffer_size {
+        options = options.write_buffer_size(size);
+    }
+    if let Some(sync) = sync_policy(cli) {
+        options = options.sync(sync);
+    }
+    options
+}
+
+fn sync_policy(cli: &Cli) -> Option<SyncPolicy> {
+    cli.sync.map(|sync| match sync {
+        Sync::Never => SyncPolicy::Never,
+        Sync::Always => SyncPolicy::Always,
+        Sync::Group => SyncPolicy::GroupCommit,
+        Sync::Interval => SyncPolicy::Interval(Duration::from_millis(cli.sync_interval)),
+    })
 }
 
 fn get_current_engine() -> Result<Option<Engine>> {
@@ -65,7 +316,7 @@ fn get_current_engine() -> Result<Option<Engine>> {
         },
     };
     let engine: Engine =
-        serde_json::from_str(&engine).map_err(|val| (format!("Corrupted engine value {}", val)))?;
+        serde_json::from_str(&engine).map_err(|val| format!("Corrupted engine value {}", val))?;
     Ok(Some(engine))
 }
 
@@ -85,23 +336,27 @@ fn handle_engine_selection(
             Ok(engine)
         }
         (None, Some(engine)) => {
-            let content = serde_json::to_string(&engine)?;
-            let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(".engine")?;
-            file.write(content.as_bytes())?;
+            write_engine(engine)?;
             Ok(engine)
         }
         (None, None) => {
             let engine = Engine::Kvs;
-            let content = serde_json::to_string(&engine)?;
-            let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(".engine")?;
-            file.write(content.as_bytes())?;
+            write_engine(engine)?;
             Ok(engine)
         }
     }
 }
+
+/// Records `engine` as the engine of the current directory.
+fn write_engine(engine: Engine) -> Result<()> {
+    let content = serde_json::to_string(&engine)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(".engine.tmp")?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(".engine.tmp", ".engine")?;
+    Ok(())
+}
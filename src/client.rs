@@ -0,0 +1,109 @@
+use crate::{
+    codec::{self, Encoding},
+    commands::{Hello, KvsCommands, KvsResponse, ServerInfo, Welcome, PROTOCOL_VERSION},
+    Result,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    io::{BufReader, BufWriter, Write},
+    net::{TcpStream, ToSocketAddrs},
+    thread,
+};
+
+/// A connection to a `kvs-server`, kept open for any number of requests.
+pub struct KvClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    encoding: Encoding,
+    server: ServerInfo,
+}
+
+impl KvClient {
+    /// Connects to the server at `addr`, talking bincode.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Self::connect_with_encoding(addr, Encoding::Bincode)
+    }
+
+    /// Connects to the server at `addr`, asking it to talk `encoding`.
+    /// Fails if the server speaks another protocol version.
+    pub fn connect_with_encoding(addr: impl ToSocketAddrs, encoding: Encoding) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        codec::send(&mut writer, Encoding::Json, &Hello::new(vec![encoding]))?;
+        writer.flush()?;
+        match receive(&mut reader, Encoding::Json)? {
+            Welcome::Accepted { server, .. } if server.protocol_version != PROTOCOL_VERSION => {
+                Err(From::from(format!(
+                    "Server speaks protocol version {}, this client speaks {}",
+                    server.protocol_version, PROTOCOL_VERSION
+                )))
+            }
+            Welcome::Accepted { server, encoding } => Ok(KvClient {
+                reader,
+                writer,
+                encoding,
+                server,
+            }),
+            Welcome::Rejected(reason) => Err(From::from(format!(
+                "Server refused the connection: {}",
+                reason
+            ))),
+        }
+    }
+
+    /// The encoding the server agreed to.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// What the server said about itself when the connection was opened.
+    pub fn server(&self) -> &ServerInfo {
+        &self.server
+    }
+
+    /// Sends `command` and waits for its response. A failure reported by the
+    /// server comes back as the [`KvError`](crate::KvError) it stands for.
+    pub fn request(&mut self, command: &KvsCommands) -> Result<KvsResponse> {
+        codec::send(&mut self.writer, self.encoding, command)?;
+        self.writer.flush()?;
+        receive::<KvsResponse>(&mut self.reader, self.encoding)?.into_result()
+    }
+
+    /// Sends all of `commands` without waiting for responses in between,
+    /// returning the responses in the same order. Failed requests are left
+    /// as [`KvsResponse::Err`] so the others can still be read.
+    pub fn pipeline(&mut self, commands: &[KvsCommands]) -> Result<Vec<KvsResponse>> {
+        let mut frames = Vec::new();
+        for command in commands {
+            codec::send(&mut frames, self.encoding, command)?;
+        }
+        let KvClient {
+            reader,
+            writer,
+            encoding,
+            ..
+        } = self;
+        thread::scope(|scope| {
+            // writing from another thread keeps a long pipeline from filling
+            // both socket buffers while nobody reads
+            let sender = scope.spawn(move || {
+                writer.write_all(&frames)?;
+                writer.flush()
+            });
+            let responses = commands
+                .iter()
+                .map(|_| receive(reader, *encoding))
+                .collect();
+            sender.join().unwrap()?;
+            responses
+        })
+    }
+}
+
+fn receive<T: DeserializeOwned>(
+    reader: &mut BufReader<TcpStream>,
+    encoding: Encoding,
+) -> Result<T> {
+    codec::receive(reader, encoding)?.ok_or_else(|| From::from("Server closed the connection"))
+}
@@ -0,0 +1,97 @@
+//! Framing of the messages between client and server. Every message is a
+//! frame: its length as a big-endian `u32`, then that many bytes of the
+//! message in the [`Encoding`] agreed on for the connection.
+
+use crate::{KvError, Result};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Largest payload a frame may carry, in bytes.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+const LEN_PREFIX: u64 = 4;
+
+/// How messages are encoded inside their frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    /// Readable, for debugging; byte strings become arrays of numbers.
+    Json,
+    /// Compact binary, byte strings carried as they are.
+    Bincode,
+}
+
+impl Encoding {
+    pub fn encode(self, message: &impl Serialize) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Json => serde_json::to_vec(message)?,
+            // unbounded, so an oversized message fails in `write_frame` as one
+            Encoding::Bincode => bincode::DefaultOptions::new().serialize(message)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        Ok(match self {
+            Encoding::Json => serde_json::from_slice(payload)?,
+            Encoding::Bincode => bincode_options().deserialize(payload)?,
+        })
+    }
+}
+
+/// Bounded by the frame size, so a damaged length inside a message cannot
+/// make the decoder allocate more than a frame could hold.
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_FRAME_SIZE as u64)
+}
+
+/// Writes `payload` as one frame. Buffered writers are left for the caller
+/// to flush, so several frames can go out together.
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(From::from(KvError::FrameTooLarge(payload.len())));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Reads the payload of the next frame, or `None` if the peer closed the
+/// connection before starting one. A connection closed in the middle of a
+/// frame fails with [`KvError::TruncatedFrame`].
+pub fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut prefix = Vec::with_capacity(LEN_PREFIX as usize);
+    reader.take(LEN_PREFIX).read_to_end(&mut prefix)?;
+    match prefix.len() as u64 {
+        0 => return Ok(None),
+        LEN_PREFIX => {}
+        _ => return Err(From::from(KvError::TruncatedFrame)),
+    }
+    let len = u32::from_be_bytes(prefix[..].try_into()?) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(From::from(KvError::FrameTooLarge(len)));
+    }
+    // grows with what actually arrives instead of trusting the prefix up front
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(From::from(KvError::TruncatedFrame));
+    }
+    Ok(Some(payload))
+}
+
+/// Writes `message` as one frame.
+pub fn send(writer: &mut impl Write, encoding: Encoding, message: &impl Serialize) -> Result<()> {
+    write_frame(writer, &encoding.encode(message)?)
+}
+
+/// Reads the next message, or `None` if the peer closed the connection
+/// before sending one.
+pub fn receive<T: DeserializeOwned>(
+    reader: &mut impl Read,
+    encoding: Encoding,
+) -> Result<Option<T>> {
+    match read_frame(reader)? {
+        Some(payload) => Ok(Some(encoding.decode(&payload)?)),
+        None => Ok(None),
+    }
+}
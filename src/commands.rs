@@ -1,14 +1,239 @@
+use crate::{codec::Encoding, engines::WriteBatch, KvError, Result};
 use serde::{Deserialize, Serialize};
+use std::{error::Error, io, path::PathBuf};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum KvsCommands {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    /// Expires after `ttl_ms` milliseconds when given.
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        #[serde(default)]
+        ttl_ms: Option<u64>,
+    },
+    Rm {
+        key: Vec<u8>,
+    },
+    /// Entries from `start` (inclusive) to `end` (exclusive), either open when missing.
+    Scan {
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    ScanPrefix {
+        prefix: Vec<u8>,
+    },
+    Expire {
+        key: Vec<u8>,
+        ttl_ms: u64,
+    },
+    Ttl {
+        key: Vec<u8>,
+    },
+    Batch(WriteBatch),
+    /// Swaps in `new` if the value is `expected`, `None` being a missing key.
+    Cas {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    SetIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetIfPresent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Incr {
+        key: Vec<u8>,
+        by: i64,
+    },
+    Decr {
+        key: Vec<u8>,
+        by: i64,
+    },
+    Append {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// Starts a transaction, answered with its id.
+    Begin,
+    /// An operation inside transaction `id`; writes take effect on commit.
+    Txn {
+        id: u64,
+        op: TxnOp,
+    },
+    Commit {
+        id: u64,
+    },
+    Abort {
+        id: u64,
+    },
+    /// Writes a backup into `dest` on the server, a relative path inside its
+    /// data directory.
+    Backup {
+        dest: PathBuf,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum TxnOp {
+    Get { key: Vec<u8> },
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Rm { key: Vec<u8> },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum KvsResponse {
-    Ok(Option<String>),
-    Err(String),
+    Ok(Option<Vec<u8>>),
+    Entries(Vec<(Vec<u8>, Vec<u8>)>),
+    /// Milliseconds left until a key expires, `None` if it never does.
+    Ttl(Option<u64>),
+    /// Whether a conditional write took place.
+    Written(bool),
+    /// The new value of a counter, or the new length after an append.
+    Integer(i64),
+    /// Id of a transaction just begun.
+    Transaction(u64),
+    /// The request failed: `code` says how, for programs, and `message` why,
+    /// for people.
+    Err {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl KvsResponse {
+    /// The response reporting `e`.
+    pub fn error(e: &(dyn Error + 'static)) -> Self {
+        KvsResponse::Err {
+            code: ErrorCode::of(e),
+            message: e.to_string(),
+        }
+    }
+
+    /// Turns an error response back into the [`KvError`] it reports.
+    pub fn into_result(self) -> Result<Self> {
+        match self {
+            KvsResponse::Err {
+                code: ErrorCode::NotFound,
+                ..
+            } => Err(From::from(KvError::KeyNotFound)),
+            KvsResponse::Err {
+                code: ErrorCode::Conflict,
+                ..
+            } => Err(From::from(KvError::Conflict)),
+            KvsResponse::Err { code, message } => {
+                Err(From::from(KvError::Server { code, message }))
+            }
+            response => Ok(response),
+        }
+    }
+}
+
+/// The kind of failure in a [`KvsResponse::Err`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The key does not exist, or has expired.
+    NotFound,
+    /// The request cannot be served as sent and would fail again if repeated.
+    BadRequest,
+    /// A transaction read a key that was written before it committed.
+    Conflict,
+    /// A message was over [`MAX_FRAME_SIZE`](crate::codec::MAX_FRAME_SIZE).
+    TooLarge,
+    /// The server cannot take the request now, but may later.
+    ServerBusy,
+    /// The server failed in a way that is not the client's doing.
+    Internal,
+    /// The storage engine failed to read or write its data.
+    Storage,
+}
+
+impl ErrorCode {
+    /// The code for a failure while serving a request.
+    pub fn of(e: &(dyn Error + 'static)) -> Self {
+        match e.downcast_ref::<KvError>() {
+            Some(KvError::KeyNotFound) => ErrorCode::NotFound,
+            Some(KvError::Conflict) => ErrorCode::Conflict,
+            Some(
+                KvError::NotAnInteger
+                | KvError::Overflow
+                | KvError::TruncatedFrame
+                | KvError::AlreadyExists(_),
+            ) => ErrorCode::BadRequest,
+            Some(KvError::FrameTooLarge(_)) => ErrorCode::TooLarge,
+            Some(
+                KvError::Corrupt { .. }
+                | KvError::UnknownFormat
+                | KvError::UnsupportedVersion(_)
+                | KvError::Locked,
+            ) => ErrorCode::Storage,
+            Some(KvError::Server { code, .. }) => *code,
+            None if e.is::<io::Error>() || e.is::<sled::Error>() => ErrorCode::Storage,
+            None => ErrorCode::Internal,
+        }
+    }
+}
+
+/// Version of the protocol spoken by this build. Client and server only
+/// talk when they speak the same version.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Features this build's server offers, as listed in [`ServerInfo`].
+pub const FEATURES: &[&str] = &[
+    "ttl",
+    "scan",
+    "batch",
+    "cas",
+    "counters",
+    "transactions",
+    "backup",
+    "pipelining",
+];
+
+/// Opens every connection, always in JSON so that later versions can add
+/// fields without losing older peers: the encodings the client can speak,
+/// most preferred first.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub client_version: String,
+    pub encodings: Vec<Encoding>,
+}
+
+impl Hello {
+    pub fn new(encodings: Vec<Encoding>) -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_version: env!("CARGO_PKG_VERSION").to_owned(),
+            encodings,
+        }
+    }
+}
+
+/// What a server tells its clients about itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub protocol_version: u32,
+    pub server_version: String,
+    /// Name of the storage engine, see [`KvsEngine::name`](crate::engines::KvsEngine::name).
+    pub engine: String,
+    /// Kept as strings so clients can be told about features newer than they are.
+    pub features: Vec<String>,
+}
+
+/// The server's answer to [`Hello`], also in JSON. Everything after it is
+/// sent in the encoding the server picked.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Welcome {
+    Accepted {
+        server: ServerInfo,
+        encoding: Encoding,
+    },
+    Rejected(String),
 }
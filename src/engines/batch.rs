@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+
+/// One mutation of a [`WriteBatch`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+/// Sets and removals applied together by [`KvsEngine::write_batch`]: either
+/// all of them take effect or, after a crash, none do. Later operations on a
+/// key win over earlier ones, and removing a missing key is not an error.
+///
+/// [`KvsEngine::write_batch`]: super::KvsEngine::write_batch
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+impl From<Vec<BatchOp>> for WriteBatch {
+    fn from(ops: Vec<BatchOp>) -> Self {
+        WriteBatch { ops }
+    }
+}
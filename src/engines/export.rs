@@ -0,0 +1,152 @@
+use super::{
+    migrate::{for_each_page, write_page, Entry, PAGE_SIZE},
+    KvsEngine,
+};
+use crate::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    time::Duration,
+};
+
+/// File formats for [`export`] and [`import`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line with the fields `key`, `value` and the
+    /// optional `encoding` and `ttl_ms`.
+    JsonLines,
+    /// A `key,value,encoding,ttl_ms` header, then one row per key.
+    Csv,
+}
+
+const CSV_HEADER: [&str; 4] = ["key", "value", "encoding", "ttl_ms"];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+    /// Set when key and value are base64, because one of them is not UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<Encoding>,
+    /// Milliseconds the key has left to live.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    Base64,
+}
+
+impl Record {
+    fn new((key, value, ttl): Entry) -> Self {
+        let ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+        match (String::from_utf8(key), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => Record {
+                key,
+                value,
+                encoding: None,
+                ttl_ms,
+            },
+            (key, value) => Record {
+                key: STANDARD.encode(key.map_or_else(|e| e.into_bytes(), String::into_bytes)),
+                value: STANDARD.encode(value.map_or_else(|e| e.into_bytes(), String::into_bytes)),
+                encoding: Some(Encoding::Base64),
+                ttl_ms,
+            },
+        }
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        let ttl = self.ttl_ms.map(Duration::from_millis);
+        Ok(match self.encoding {
+            None => (self.key.into_bytes(), self.value.into_bytes(), ttl),
+            Some(Encoding::Base64) => (
+                STANDARD.decode(self.key)?,
+                STANDARD.decode(self.value)?,
+                ttl,
+            ),
+        })
+    }
+
+    fn csv_row(&self) -> [String; 4] {
+        [
+            self.key.clone(),
+            self.value.clone(),
+            self.encoding.map_or("", |_| "base64").to_owned(),
+            self.ttl_ms.map_or_else(String::new, |ttl| ttl.to_string()),
+        ]
+    }
+}
+
+/// Writes every key of `engine` to `writer` in key order and returns how
+/// many were written. Keys and values that are not UTF-8 are base64 encoded.
+pub fn export(engine: &impl KvsEngine, format: Format, writer: impl Write) -> Result<u64> {
+    let mut count = 0;
+    match format {
+        Format::JsonLines => {
+            let mut writer = BufWriter::new(writer);
+            for_each_page(engine, |page| {
+                for entry in page {
+                    serde_json::to_writer(&mut writer, &Record::new(entry))?;
+                    writer.write_all(b"\n")?;
+                    count += 1;
+                }
+                Ok(())
+            })?;
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(CSV_HEADER)?;
+            for_each_page(engine, |page| {
+                for entry in page {
+                    writer.write_record(Record::new(entry).csv_row())?;
+                    count += 1;
+                }
+                Ok(())
+            })?;
+            writer.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+/// Loads keys written by [`export`] into `engine`, overwriting keys it
+/// already holds, and returns how many were loaded. Keys are written in
+/// batches, so a failed import may leave some of them behind.
+pub fn import(engine: &impl KvsEngine, format: Format, reader: impl Read) -> Result<u64> {
+    let mut count = 0;
+    let mut page = Vec::with_capacity(PAGE_SIZE);
+    let mut load = |record: Record| -> Result<()> {
+        page.push(record.into_entry()?);
+        count += 1;
+        if page.len() == PAGE_SIZE {
+            write_page(engine, std::mem::take(&mut page))?;
+        }
+        Ok(())
+    };
+    match format {
+        Format::JsonLines => {
+            for (n, line) in BufReader::new(reader).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                serde_json::from_str(&line)
+                    .map_err(From::from)
+                    .and_then(&mut load)
+                    .map_err(|e| format!("Line {}: {}", n + 1, e))?;
+            }
+        }
+        Format::Csv => {
+            for record in csv::Reader::from_reader(reader).deserialize() {
+                load(record?)?;
+            }
+        }
+    }
+    write_page(engine, page)?;
+    Ok(count)
+}
@@ -0,0 +1,60 @@
+use crate::Result;
+use std::sync::{Condvar, Mutex};
+
+/// Lets concurrent writers share a single sync.
+///
+/// Every writer takes a ticket once its write has reached the OS and then
+/// waits until a sync started after that point has finished. Whoever finds no
+/// sync in flight runs one on behalf of everybody who is waiting.
+#[derive(Debug, Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<State>,
+    synced_cond: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    written: u64,
+    synced: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    /// Records a finished write, returning the ticket to wait on.
+    pub fn ticket(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    /// The ticket of the latest write.
+    pub fn last_ticket(&self) -> u64 {
+        self.state.lock().unwrap().written
+    }
+
+    /// Blocks until the write behind `ticket` is synced, running `sync` if no
+    /// other writer is already doing so.
+    pub fn wait(&self, ticket: u64, sync: impl Fn() -> Result<()>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced_cond.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            let target = state.written;
+            drop(state);
+            let res = sync();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if res.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced_cond.notify_all();
+            res?;
+        }
+    }
+}
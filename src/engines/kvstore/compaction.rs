@@ -0,0 +1,199 @@
+use super::{
+    hint, CommandPos, HintEntry, Index, KvStoreOptions, Layout, Pins, SegmentWriter, WriteAgent,
+};
+use crate::Result;
+use log::error;
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    mem,
+    ops::RangeInclusive,
+    sync::{mpsc, Arc, Mutex, RwLock},
+    thread::{self, JoinHandle},
+};
+
+pub(super) enum Job {
+    Compact,
+    Shutdown,
+}
+
+/// Handle to the thread compacting the store in the background.
+/// Dropping it waits for a running compaction to finish.
+#[derive(Debug)]
+pub(super) struct Compactor {
+    sender: mpsc::Sender<Job>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub fn spawn(
+        receiver: mpsc::Receiver<Job>,
+        sender: mpsc::Sender<Job>,
+        write_agent: Arc<Mutex<WriteAgent>>,
+        index: Arc<RwLock<Index>>,
+    ) -> Result<Self> {
+        let thread = thread::Builder::new()
+            .name("kvs-compactor".to_owned())
+            .spawn(move || {
+                while let Ok(Job::Compact) = receiver.recv() {
+                    if let Err(e) = compact(&write_agent, &index) {
+                        error!("Compaction failed: {}", e);
+                    }
+                    write_agent.lock().unwrap().compacting = false;
+                }
+            })?;
+        Ok(Compactor {
+            sender,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Job::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// What a compaction run copies and where it puts it.
+struct Plan {
+    layout: Layout,
+    options: Arc<KvStoreOptions>,
+    /// Generations reserved for the compacted segments.
+    first_gen: u64,
+    last_gen: u64,
+    /// Live records at the time the active segment was sealed, in file order.
+    entries: Vec<(Vec<u8>, CommandPos)>,
+    /// Stale bytes accounted to the segments being replaced.
+    stale_bytes: u64,
+    pins: Arc<Pins>,
+}
+
+/// Copies every live record into new segments and deletes all the older ones.
+///
+/// The compacted segments take the generations right after the active one,
+/// and writing continues in a segment after those, so replaying segments in
+/// generation order still yields the newest value of every key. Only sealing
+/// the active segment and swapping the index happen under a lock; readers and
+/// writers carry on while records are copied.
+pub(super) fn compact(write_agent: &Mutex<WriteAgent>, index: &RwLock<Index>) -> Result<()> {
+    let mut plan = {
+        let mut agent = write_agent.lock().unwrap();
+        // expired keys are left behind with the old segments
+        agent.sweep();
+        let entries: Vec<(Vec<u8>, CommandPos)> = index
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, pos)| (key.clone(), *pos))
+            .collect();
+        let live_bytes: u64 = entries.iter().map(|(_, pos)| pos.len).sum();
+        let first_gen = agent.writer.gen + 1;
+        let last_gen = first_gen + live_bytes.div_ceil(agent.options.max_segment_size).max(1) - 1;
+        agent.switch_segment(last_gen + 1)?;
+        Plan {
+            layout: agent.layout.clone(),
+            options: agent.options.clone(),
+            first_gen,
+            last_gen,
+            entries,
+            stale_bytes: mem::take(&mut agent.stale_bytes),
+            pins: agent.pins.clone(),
+        }
+    };
+    plan.entries
+        .sort_unstable_by_key(|(_, pos)| (pos.gen, pos.pos));
+
+    let moved = match copy_records(&plan) {
+        Ok(moved) => moved,
+        Err(e) => {
+            write_agent.lock().unwrap().stale_bytes += plan.stale_bytes;
+            return Err(e);
+        }
+    };
+    {
+        let mut index = index.write().unwrap();
+        for (key, old, new) in moved {
+            match index.get_mut(&key) {
+                // keys written since compaction started already point past the copies
+                Some(pos) if pos.gen == old.gen && pos.pos == old.pos => *pos = new,
+                _ => {}
+            }
+        }
+    }
+
+    let old_gens = plan
+        .layout
+        .sorted_gens()?
+        .into_iter()
+        .filter(|&gen| gen < plan.first_gen)
+        .collect();
+    plan.pins.retire(&plan.layout, old_gens)
+}
+
+/// Writes the records of `plan` into the compacted segments, returning each key
+/// with its old and new position.
+fn copy_records(plan: &Plan) -> Result<Vec<(Vec<u8>, CommandPos, CommandPos)>> {
+    copy_into(
+        &plan.layout,
+        &plan.layout,
+        &plan.options,
+        &plan.entries,
+        plan.first_gen..=plan.last_gen,
+    )
+}
+
+/// Copies the records at `entries`, given in file order, from the segments of
+/// `source` into new segments of `target` with generations from `gens`, each
+/// with a hint file. Returns each key with its old and new position.
+pub(super) fn copy_into(
+    source: &Layout,
+    target: &Layout,
+    options: &KvStoreOptions,
+    entries: &[(Vec<u8>, CommandPos)],
+    gens: RangeInclusive<u64>,
+) -> Result<Vec<(Vec<u8>, CommandPos, CommandPos)>> {
+    let mut moved = Vec::with_capacity(entries.len());
+    let mut output = SegmentWriter::create_temp(target, *gens.start(), options.write_buffer_size)?;
+    let mut hints = Vec::new();
+    let mut reader: Option<(u64, BufReader<File>)> = None;
+    for (key, item) in entries {
+        let file_reader = match &mut reader {
+            Some((gen, file_reader)) if *gen == item.gen => file_reader,
+            _ => {
+                let file = File::open(source.segment(item.gen))?;
+                let file_reader = BufReader::with_capacity(options.read_buffer_size, file);
+                &mut reader.insert((item.gen, file_reader)).1
+            }
+        };
+        file_reader.seek(SeekFrom::Start(item.pos))?;
+        let mut bytes = vec![0u8; item.len as usize];
+        file_reader.read_exact(&mut bytes)?;
+        if output.len >= options.max_segment_size && output.gen < *gens.end() {
+            let gen = output.gen;
+            output.finish(target)?;
+            hint::write(target, gen, &hints)?;
+            hints.clear();
+            output = SegmentWriter::create_temp(target, gen + 1, options.write_buffer_size)?;
+        }
+        let pos = CommandPos {
+            gen: output.gen,
+            pos: output.append(&bytes)?,
+            ..*item
+        };
+        hints.push(HintEntry {
+            key: key.clone(),
+            pos: pos.pos,
+            len: pos.len,
+            expires_at: pos.expires_at,
+        });
+        moved.push((key.clone(), *item, pos));
+    }
+    let gen = output.gen;
+    output.finish(target)?;
+    hint::write(target, gen, &hints)?;
+    Ok(moved)
+}
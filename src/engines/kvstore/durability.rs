@@ -0,0 +1,71 @@
+//! Makes appended records durable according to the store's [`SyncPolicy`].
+
+use super::segment::SegmentWriter;
+use crate::{
+    engines::{group_commit::GroupCommit, SyncPolicy},
+    Result,
+};
+use std::{fs::File, sync::Mutex};
+
+#[derive(Debug)]
+pub(super) struct Durability {
+    policy: SyncPolicy,
+    /// Second handle on the active segment, so it can be synced without
+    /// holding the write lock.
+    active: Mutex<File>,
+    group: GroupCommit,
+}
+
+impl Durability {
+    pub fn new(policy: SyncPolicy, writer: &SegmentWriter) -> Result<Self> {
+        Ok(Durability {
+            policy,
+            active: Mutex::new(writer.sync_handle()?),
+            group: GroupCommit::default(),
+        })
+    }
+
+    /// Called under the write lock once `writer` has taken a record. Returns
+    /// the ticket to pass to [`Durability::wait`] after the lock is released.
+    pub fn appended(&self, writer: &mut SegmentWriter) -> Result<u64> {
+        match self.policy {
+            SyncPolicy::Always => writer.sync()?,
+            _ => writer.flush()?,
+        }
+        match self.policy {
+            SyncPolicy::GroupCommit | SyncPolicy::Interval(_) => Ok(self.group.ticket()),
+            SyncPolicy::Never | SyncPolicy::Always => Ok(0),
+        }
+    }
+
+    /// Called under the write lock when appends move from `old` on to `new`.
+    /// `old` is synced right away since later syncs only cover the active segment.
+    pub fn switched(&self, old: &mut SegmentWriter, new: &SegmentWriter) -> Result<()> {
+        match self.policy {
+            SyncPolicy::Never => old.flush()?,
+            _ => old.sync()?,
+        }
+        *self.active.lock().unwrap() = new.sync_handle()?;
+        Ok(())
+    }
+
+    /// Blocks until the write behind `ticket` is on disk, if the policy asks
+    /// writers to wait for that.
+    pub fn wait(&self, ticket: u64) -> Result<()> {
+        if self.policy != SyncPolicy::GroupCommit {
+            return Ok(());
+        }
+        self.group.wait(ticket, || self.sync_active())
+    }
+
+    /// Syncs everything written so far, unless that already happened.
+    pub fn sync_written(&self) -> Result<()> {
+        self.group
+            .wait(self.group.last_ticket(), || self.sync_active())
+    }
+
+    fn sync_active(&self) -> Result<()> {
+        self.active.lock().unwrap().sync_data()?;
+        Ok(())
+    }
+}
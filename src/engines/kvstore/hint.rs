@@ -0,0 +1,116 @@
+//! Hint files list where every record of a compacted segment lives, so opening
+//! the store can rebuild the index from them without reading any values.
+
+use super::segment::{self, Layout, FORMAT_VERSION};
+use crate::Result;
+use log::warn;
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+/// `crc | pos | len | expires_at | key_len`, the checksum covering everything after itself.
+const ENTRY_HEADER_LEN: usize = 4 + 8 + 8 + 8 + 4;
+
+/// Location of one record inside the segment a hint file belongs to.
+#[derive(Debug)]
+pub(super) struct HintEntry {
+    pub key: Vec<u8>,
+    pub pos: u64,
+    pub len: u64,
+    pub expires_at: u64,
+}
+
+/// Writes the hint file of segment `gen`, replacing any previous one.
+pub(super) fn write(layout: &Layout, gen: u64, entries: &[HintEntry]) -> Result<()> {
+    let temp_path = Layout::temp(&layout.hint(gen));
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    writer.write_all(&segment::header_bytes())?;
+    for entry in entries {
+        let mut bytes = Vec::with_capacity(ENTRY_HEADER_LEN + entry.key.len());
+        bytes.extend_from_slice(&[0u8; 4]);
+        bytes.extend_from_slice(&entry.pos.to_le_bytes());
+        bytes.extend_from_slice(&entry.len.to_le_bytes());
+        bytes.extend_from_slice(&entry.expires_at.to_le_bytes());
+        bytes.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&entry.key);
+        let crc = crc32fast::hash(&bytes[4..]);
+        bytes[..4].copy_from_slice(&crc.to_le_bytes());
+        writer.write_all(&bytes)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(temp_path, layout.hint(gen))?;
+    layout.sync_dir()?;
+    Ok(())
+}
+
+/// Reads the hint file of segment `gen`.
+///
+/// Returns `None` when there is no usable hint file, in which case the segment
+/// itself has to be scanned.
+pub(super) fn read(
+    layout: &Layout,
+    gen: u64,
+    buffer_size: usize,
+) -> Result<Option<Vec<HintEntry>>> {
+    let file = match File::open(layout.hint(gen)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let mut reader = BufReader::with_capacity(buffer_size, file);
+    match segment::read_header(&mut reader) {
+        Ok(Some(FORMAT_VERSION)) => {}
+        _ => {
+            warn!(
+                "Ignoring hint file of segment {} with an unknown header",
+                gen
+            );
+            return Ok(None);
+        }
+    }
+
+    let mut entries = Vec::new();
+    loop {
+        let mut header = [0u8; ENTRY_HEADER_LEN];
+        match segment::read_full(&mut reader, &mut header)? {
+            0 => break,
+            n if n == header.len() => {}
+            _ => return Ok(damaged(gen)),
+        }
+        let crc = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let pos = u64::from_le_bytes(header[4..12].try_into()?);
+        let len = u64::from_le_bytes(header[12..20].try_into()?);
+        let expires_at = u64::from_le_bytes(header[20..28].try_into()?);
+        let key_length = u32::from_le_bytes(header[28..32].try_into()?) as u64;
+        let mut key = Vec::new();
+        (&mut reader).take(key_length).read_to_end(&mut key)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&key);
+        if key.len() as u64 != key_length || hasher.finalize() != crc {
+            return Ok(damaged(gen));
+        }
+        entries.push(HintEntry {
+            key,
+            pos,
+            len,
+            expires_at,
+        });
+    }
+    Ok(Some(entries))
+}
+
+fn damaged(gen: u64) -> Option<Vec<HintEntry>> {
+    warn!("Ignoring damaged hint file of segment {}", gen);
+    None
+}
+
+/// Deletes the hint file of segment `gen`, if it has one.
+pub(super) fn remove(layout: &Layout, gen: u64) -> Result<()> {
+    match fs::remove_file(layout.hint(gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Box::new(e)),
+        _ => Ok(()),
+    }
+}
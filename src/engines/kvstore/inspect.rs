@@ -0,0 +1,246 @@
+//! Offline access to the segments of a store record by record, for looking
+//! into and repairing a data directory without opening it.
+
+use super::{
+    hint, is_expired, now_millis,
+    segment::{self, Layout, ReadResult, Record},
+    CommandPos, Index, KvStoreOptions, StoreStats,
+};
+use crate::{KvError, Result};
+use log::warn;
+use std::{
+    fs::{self, File},
+    io::{BufReader, Seek},
+    path::Path,
+};
+
+/// One record of a segment as found on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentRecord {
+    pub gen: u64,
+    /// Offset of the record in its segment.
+    pub offset: u64,
+    /// Size of the record on disk, header included.
+    pub len: u64,
+    pub key: Vec<u8>,
+    /// Length of the value of a `set`, `None` for a removal.
+    pub value_len: Option<u64>,
+    /// When a `set` expires, in milliseconds since the Unix epoch; 0 for never.
+    pub expires_at: u64,
+    /// Whether the record is part of a write batch.
+    pub in_batch: bool,
+}
+
+impl SegmentRecord {
+    fn new(gen: u64, offset: u64, len: u64, record: Record, in_batch: bool) -> Self {
+        SegmentRecord {
+            gen,
+            offset,
+            len,
+            value_len: record.value.as_ref().map(|value| value.len() as u64),
+            key: record.key,
+            expires_at: record.expires_at,
+            in_batch,
+        }
+    }
+}
+
+/// The unreadable tail of a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Damage {
+    pub gen: u64,
+    /// Offset of the first record that could not be read, 0 for a bad header.
+    pub offset: u64,
+    /// Bytes from `offset` to the end of the segment.
+    pub len: u64,
+}
+
+/// Reads every segment of the store in `path`, oldest first, calling `f`
+/// with each record. Hint files are ignored, so every checksum is checked.
+///
+/// A segment is read up to its first unreadable record; what is left of each
+/// damaged segment is returned. Fails with [`KvError::Locked`] while the
+/// store is open elsewhere.
+pub fn inspect(
+    path: &Path,
+    options: &KvStoreOptions,
+    mut f: impl FnMut(&SegmentRecord),
+) -> Result<Vec<Damage>> {
+    let layout = existing(path, options)?;
+    let _lock = layout.lock()?;
+    walk(&layout, options, &mut f)
+}
+
+/// Cuts every damaged segment of the store in `path` back to its last
+/// readable record, returning what was cut off. Sealed segments without a
+/// readable header are deleted.
+///
+/// Unlike [`KvStore::open`](super::KvStore::open), which only drops a torn
+/// record at the end of the active segment, this gives up on any data after
+/// the damage.
+pub fn repair(path: &Path, options: &KvStoreOptions) -> Result<Vec<Damage>> {
+    let layout = existing(path, options)?;
+    let _lock = layout.lock()?;
+    layout.remove_temp_files()?;
+    let damage = walk(&layout, options, &mut |_| {})?;
+    for d in &damage {
+        warn!(
+            "Cutting {} bytes off segment {} at offset {}",
+            d.len, d.gen, d.offset
+        );
+        // the hint file may point past the cut
+        hint::remove(&layout, d.gen)?;
+        if d.offset == 0 {
+            fs::remove_file(layout.segment(d.gen))?;
+        } else {
+            layout.truncate(d.gen, d.offset)?;
+        }
+    }
+    Ok(damage)
+}
+
+/// Deletes the store in `path`: its segments, hint files and lock file.
+/// Fails with [`KvError::Locked`] while it is open elsewhere.
+pub fn remove_store(path: &Path, options: &KvStoreOptions) -> Result<()> {
+    let layout = existing(path, options)?;
+    let lock = layout.lock()?;
+    layout.remove_temp_files()?;
+    for gen in layout.sorted_gens()? {
+        hint::remove(&layout, gen)?;
+        fs::remove_file(layout.segment(gen))?;
+    }
+    drop(lock);
+    fs::remove_file(layout.lock_file())?;
+    Ok(())
+}
+
+/// Works out the [`StoreStats`] of the store in `path` from its segments, as
+/// they would be right after opening it, without opening it. Fails with
+/// [`KvError::Corrupt`] if a segment is damaged.
+pub fn stats(path: &Path, options: &KvStoreOptions) -> Result<StoreStats> {
+    let layout = existing(path, options)?;
+    let _lock = layout.lock()?;
+    let index = live_index(&layout, options)?;
+    let gens = layout.sorted_gens()?;
+    let mut bytes = 0;
+    for &gen in &gens {
+        // the active segment may not have its header yet
+        bytes += fs::metadata(layout.segment(gen))?
+            .len()
+            .saturating_sub(segment::HEADER_LEN);
+    }
+    let live_bytes = index.values().map(|pos| pos.len).sum();
+    Ok(StoreStats {
+        keys: index.len(),
+        segments: gens.len(),
+        live_bytes,
+        stale_bytes: bytes - live_bytes,
+    })
+}
+
+/// Tells whether `path` holds the segments of a store.
+pub fn is_store(path: &Path, options: &KvStoreOptions) -> Result<bool> {
+    Ok(!Layout::new(path, &options.file_name)
+        .sorted_gens()?
+        .is_empty())
+}
+
+/// The layout of the store in `path`, failing if there is none so that
+/// nothing is created in a mistyped directory.
+pub(super) fn existing(path: &Path, options: &KvStoreOptions) -> Result<Layout> {
+    if !is_store(path, options)? {
+        return Err(From::from(format!("{} holds no store", path.display())));
+    }
+    Ok(Layout::new(path, &options.file_name))
+}
+
+/// The live record of every key in `layout` that has not expired, read
+/// without taking the lock. Fails with [`KvError::Corrupt`] if a segment is
+/// damaged.
+pub(super) fn live_index(layout: &Layout, options: &KvStoreOptions) -> Result<Index> {
+    let now = now_millis();
+    let mut index = Index::new();
+    let damage = walk(layout, options, &mut |record| match record.value_len {
+        Some(_) if !is_expired(record.expires_at, now) => {
+            let pos = CommandPos::new(record.gen, record.offset, record.len, record.expires_at);
+            index.insert(record.key.clone(), pos);
+        }
+        _ => {
+            index.remove(&record.key);
+        }
+    })?;
+    match damage.first() {
+        Some(damage) => Err(From::from(KvError::Corrupt {
+            gen: damage.gen,
+            offset: damage.offset,
+        })),
+        None => Ok(index),
+    }
+}
+
+/// Reads every segment in `layout` without taking the lock, see [`inspect`].
+pub(super) fn walk(
+    layout: &Layout,
+    options: &KvStoreOptions,
+    f: &mut dyn FnMut(&SegmentRecord),
+) -> Result<Vec<Damage>> {
+    let mut damage = Vec::new();
+    let gens = layout.sorted_gens()?;
+    let active_gen = gens.last().copied();
+    for gen in gens {
+        let file = File::open(layout.segment(gen))?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::with_capacity(options.read_buffer_size, file);
+        let version = match segment::read_header(&mut reader) {
+            Ok(Some(version)) if version <= segment::FORMAT_VERSION => Some(version),
+            Ok(Some(version)) => return Err(From::from(KvError::UnsupportedVersion(version))),
+            // left by a crash right after creating it; opening the store
+            // starts it over, so there is nothing to lose
+            Ok(None) if Some(gen) == active_gen => continue,
+            Ok(None) => None,
+            Err(e) if matches!(e.downcast_ref(), Some(KvError::UnknownFormat)) => None,
+            Err(e) => return Err(e),
+        };
+        let Some(version) = version else {
+            damage.push(Damage {
+                gen,
+                offset: 0,
+                len: file_len,
+            });
+            continue;
+        };
+        let mut offset = segment::HEADER_LEN;
+        loop {
+            let result = segment::read_record(&mut reader, version)?;
+            let end = reader.stream_position()?;
+            match result {
+                ReadResult::Record(record) => f(&SegmentRecord::new(
+                    gen,
+                    offset,
+                    end - offset,
+                    record,
+                    false,
+                )),
+                ReadResult::Batch(records) => {
+                    let mut pos = offset + segment::record_header_len(version);
+                    for record in records {
+                        let len = record.len_in(version);
+                        f(&SegmentRecord::new(gen, pos, len, record, true));
+                        pos += len;
+                    }
+                }
+                ReadResult::End => break,
+                ReadResult::Truncated | ReadResult::Corrupt { .. } => {
+                    damage.push(Damage {
+                        gen,
+                        offset,
+                        len: file_len - offset,
+                    });
+                    break;
+                }
+            }
+            offset = end;
+        }
+    }
+    Ok(damage)
+}
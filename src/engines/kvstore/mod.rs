@@ -0,0 +1,887 @@
+use super::{
+    add_to,
+    batch::{BatchOp, WriteBatch},
+    expiry_after, is_empty_range, is_expired, now_millis,
+    periodic::Periodic,
+    time_left, KvsEngine, SyncPolicy, SWEEP_INTERVAL,
+};
+use crate::KvError;
+use crate::Result;
+use compaction::{Compactor, Job};
+use durability::Durability;
+use hint::HintEntry;
+pub use inspect::{inspect, is_store, remove_store, repair, stats, Damage, SegmentRecord};
+use log::{error, info, warn};
+pub use options::{CompactionTrigger, KvStoreOptions};
+use segment::{Layout, ReadResult, Record, SegmentWriter};
+use snapshot::Pins;
+pub use snapshot::Snapshot;
+use std::sync::RwLock;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fs::{self, File},
+    io::{BufReader, Read, Seek, SeekFrom},
+    mem,
+    ops::RangeBounds,
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+mod compaction;
+mod durability;
+mod hint;
+mod inspect;
+mod options;
+mod segment;
+mod snapshot;
+
+#[derive(Debug)]
+pub struct KvStore {
+    store: Arc<RwLock<Index>>,
+    layout: Layout,
+    options: Arc<KvStoreOptions>,
+    write_agent: Arc<Mutex<WriteAgent>>,
+    durability: Arc<Durability>,
+    compactor: Arc<Compactor>,
+    sweeper: Arc<Periodic>,
+    syncer: Option<Arc<Periodic>>,
+    /// Released last, once the background threads are done with the files.
+    lock: Arc<File>,
+}
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            layout: self.layout.clone(),
+            options: self.options.clone(),
+            write_agent: self.write_agent.clone(),
+            durability: self.durability.clone(),
+            compactor: self.compactor.clone(),
+            sweeper: self.sweeper.clone(),
+            syncer: self.syncer.clone(),
+            lock: self.lock.clone(),
+        }
+    }
+}
+
+/// How much of the log is live, as the store accounts for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Keys in the index, including expired ones not swept yet.
+    pub keys: usize,
+    pub segments: usize,
+    /// Bytes of the records the index points at.
+    pub live_bytes: u64,
+    /// Bytes of superseded records, removals and batch headers that
+    /// compaction would reclaim.
+    pub stale_bytes: u64,
+}
+
+/// Where the live record of every key is, ordered by key. A persistent map,
+/// so a snapshot shares it with the store instead of copying it.
+type Index = im::OrdMap<Vec<u8>, CommandPos>;
+
+#[derive(Debug, Clone, Copy)]
+struct CommandPos {
+    pub gen: u64,
+    pub pos: u64,
+    pub len: u64,
+    /// When the key expires, in milliseconds since the Unix epoch; 0 for never.
+    pub expires_at: u64,
+    /// Changes with every write of the key, see [`KvsEngine::get_versioned`].
+    /// Keys loaded from disk all start at 1, missing keys are at 0.
+    pub version: u64,
+}
+impl CommandPos {
+    fn new(gen: u64, pos: u64, len: u64, expires_at: u64) -> Self {
+        CommandPos {
+            gen,
+            pos,
+            len,
+            expires_at,
+            version: 1,
+        }
+    }
+}
+
+/// Reads values from the segments, keeping the last segment it used open.
+struct RecordReader<'a> {
+    layout: &'a Layout,
+    buffer_size: usize,
+    segment: Option<(u64, BufReader<File>)>,
+}
+
+impl RecordReader<'_> {
+    /// Reads the value of the `set` record at `pos`.
+    fn read_value(&mut self, pos: CommandPos) -> Result<Vec<u8>> {
+        let file_reader = match &mut self.segment {
+            Some((gen, file_reader)) if *gen == pos.gen => file_reader,
+            _ => {
+                let file = File::open(self.layout.segment(pos.gen))?;
+                let file_reader = BufReader::with_capacity(self.buffer_size, file);
+                &mut self.segment.insert((pos.gen, file_reader)).1
+            }
+        };
+        file_reader.seek(SeekFrom::Start(pos.pos))?;
+        match segment::read_record(&mut file_reader.take(pos.len), segment::FORMAT_VERSION)? {
+            ReadResult::Record(Record {
+                value: Some(value), ..
+            }) => Ok(value),
+            _ => Err(From::from(KvError::Corrupt {
+                gen: pos.gen,
+                offset: pos.pos,
+            })),
+        }
+    }
+}
+
+impl Default for KvStore {
+    fn default() -> Self {
+        Self::open(
+            env::current_dir()
+                .expect("Error getting current dir")
+                .as_path(),
+        )
+        .expect("Error creating KvStore")
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(|agent| agent.set(key, value, 0))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let index = self.store.read().unwrap();
+        match index.get(&key) {
+            Some(&pos) if !is_expired(pos.expires_at, now_millis()) => {
+                self.reader().read_value(pos).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn scan(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        // holding the index keeps compaction from deleting the segments being read
+        let index = self.store.read().unwrap();
+        read_range(&index, &mut self.reader(), range, limit, now_millis())
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.write(|agent| agent.remove(key))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write(|agent| agent.write_batch(batch))
+    }
+
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        let index = self.store.read().unwrap();
+        match index.get(&key) {
+            Some(&pos) if !is_expired(pos.expires_at, now_millis()) => {
+                Ok((Some(self.reader().read_value(pos)?), pos.version))
+            }
+            _ => Ok((None, 0)),
+        }
+    }
+
+    fn commit_if_unchanged(
+        &self,
+        reads: &BTreeMap<Vec<u8>, u64>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        self.write(|agent| agent.commit_if_unchanged(reads, batch))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.write(|agent| agent.compare_and_swap(key, expected, new))
+    }
+
+    fn set_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.write(|agent| agent.set_if_present(key, value))
+    }
+
+    fn increment(&self, key: Vec<u8>, by: i64) -> Result<i64> {
+        self.write(|agent| agent.increment(key, by))
+    }
+
+    fn append(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        self.write(|agent| agent.append_value(key, suffix))
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(|agent| agent.set(key, value, expiry_after(ttl)))
+    }
+
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(|agent| agent.expire(key, expiry_after(ttl)))
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.store.read().unwrap().get(&key) {
+            Some(pos) if !is_expired(pos.expires_at, now_millis()) => Ok(time_left(pos.expires_at)),
+            _ => Err(From::from(KvError::KeyNotFound)),
+        }
+    }
+
+    fn backup(&self, dest: &Path) -> Result<()> {
+        self.snapshot().backup(dest)
+    }
+}
+
+impl KvStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens the store in `path`, tuned by `options`.
+    ///
+    /// Fails with [`KvError::Locked`] while the store is open elsewhere.
+    pub fn open_with_options(path: &Path, options: KvStoreOptions) -> Result<Self> {
+        options.validate()?;
+        let options = Arc::new(options);
+        let layout = Layout::new(path, &options.file_name);
+        let lock = layout.lock()?;
+        layout.remove_temp_files()?;
+        let legacy = layout.legacy();
+        let mut gens = layout.sorted_gens()?;
+        if gens.is_empty() && legacy.is_file() {
+            upgrade(&layout, &options, &legacy, 0, 1)?;
+            fs::remove_file(&legacy)?;
+            gens.push(1);
+        }
+
+        let mut hashmap = Index::new();
+        let mut stale_bytes = 0;
+        for (i, &gen) in gens.iter().enumerate() {
+            let is_active = i == gens.len() - 1;
+            stale_bytes += load_segment(&layout, &options, gen, &mut hashmap, is_active)?;
+        }
+        let live_bytes = hashmap.values().map(|pos| pos.len).sum();
+        let expiring = hashmap
+            .iter()
+            .filter(|(_, pos)| pos.expires_at != 0)
+            .map(|(key, pos)| (pos.expires_at, key.clone()))
+            .collect();
+        let store = Arc::new(RwLock::new(hashmap));
+        let active_gen = gens.last().copied().unwrap_or(1);
+        // a hint file would miss everything appended from now on
+        hint::remove(&layout, active_gen)?;
+        let (sender, receiver) = mpsc::channel();
+        let segment_writer = SegmentWriter::open(&layout, active_gen, options.write_buffer_size)?;
+        let durability = Arc::new(Durability::new(options.sync, &segment_writer)?);
+        let writer = WriteAgent {
+            index: store.clone(),
+            writer: segment_writer,
+            durability: durability.clone(),
+            ticket: 0,
+            next_version: 2,
+            layout: layout.clone(),
+            options: options.clone(),
+            stale_bytes,
+            live_bytes,
+            expiring,
+            jobs: sender.clone(),
+            compacting: false,
+            pins: Arc::default(),
+        };
+        let writer = Arc::new(Mutex::new(writer));
+        writer.lock().unwrap().maybe_compact();
+        let compactor = Compactor::spawn(receiver, sender, writer.clone(), store.clone())?;
+        let sweeper = {
+            let writer = writer.clone();
+            Periodic::spawn("kvs-sweeper", SWEEP_INTERVAL, move || {
+                writer.lock().unwrap().sweep();
+            })?
+        };
+        let syncer = match options.sync {
+            SyncPolicy::Interval(interval) => {
+                let durability = durability.clone();
+                Some(Arc::new(Periodic::spawn(
+                    "kvs-syncer",
+                    interval,
+                    move || {
+                        if let Err(e) = durability.sync_written() {
+                            error!("Sync failed: {}", e);
+                        }
+                    },
+                )?))
+            }
+            _ => None,
+        };
+        Ok(KvStore {
+            store: store.clone(),
+            layout,
+            options,
+            write_agent: writer,
+            durability,
+            compactor: Arc::new(compactor),
+            sweeper: Arc::new(sweeper),
+            syncer,
+            lock: Arc::new(lock),
+        })
+    }
+
+    /// Rebuilds a store in `path` from a backup made by [`KvsEngine::backup`].
+    /// `path` must not hold a store yet.
+    pub fn restore(backup: &Path, path: &Path) -> Result<()> {
+        Self::restore_with_options(backup, path, KvStoreOptions::default())
+    }
+
+    /// Like [`KvStore::restore`], for stores tuned by `options`.
+    pub fn restore_with_options(backup: &Path, path: &Path, options: KvStoreOptions) -> Result<()> {
+        options.validate()?;
+        // the backup is only read, every checksum checked on the way; opening
+        // it as a store could lock, cut, upgrade or compact it
+        let source = inspect::existing(backup, &options)?;
+        for gen in source.sorted_gens()? {
+            let mut file = File::open(source.segment(gen))?;
+            match segment::read_header(&mut file)? {
+                Some(version) if version < segment::FORMAT_VERSION => {
+                    return Err(From::from(format!(
+                        "{} was written in format version {}; open a copy of it to upgrade it first",
+                        backup.display(),
+                        version
+                    )))
+                }
+                // anything else is reported by the walk
+                _ => {}
+            }
+        }
+        let index = inspect::live_index(&source, &options)?;
+        info!("Restoring {} keys", index.len());
+        snapshot::write_store(&source, path, &options, &index, now_millis())
+    }
+
+    /// Takes a read-only view of the store as it is now, unaffected by later
+    /// writes. Segments it reads from are kept until it is dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        // no write can land between taking the index and noting the position;
+        // cloning it only shares its root, so writers are not held up
+        let agent = self.write_agent.lock().unwrap();
+        agent.pins.pin();
+        Snapshot {
+            index: self.store.read().unwrap().clone(),
+            layout: self.layout.clone(),
+            options: self.options.clone(),
+            taken_at: now_millis(),
+            position: (agent.writer.gen, agent.writer.len),
+            pins: agent.pins.clone(),
+            _lock: self.lock.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> Result<StoreStats> {
+        let agent = self.write_agent.lock().unwrap();
+        Ok(StoreStats {
+            keys: self.store.read().unwrap().len(),
+            segments: self.layout.sorted_gens()?.len(),
+            live_bytes: agent.live_bytes,
+            stale_bytes: agent.stale_bytes,
+        })
+    }
+
+    /// Compacts the log now instead of waiting for the compaction trigger,
+    /// returning once it is done. Fails if a compaction is already running.
+    pub fn compact(&self) -> Result<()> {
+        {
+            let mut agent = self.write_agent.lock().unwrap();
+            if agent.compacting {
+                return Err(From::from("A compaction is already running"));
+            }
+            agent.compacting = true;
+        }
+        let res = compaction::compact(&self.write_agent, &self.store);
+        self.write_agent.lock().unwrap().compacting = false;
+        res
+    }
+
+    fn reader(&self) -> RecordReader<'_> {
+        RecordReader {
+            layout: &self.layout,
+            buffer_size: self.options.read_buffer_size,
+            segment: None,
+        }
+    }
+
+    /// Runs `op` under the write lock, then waits for the sync policy to be
+    /// satisfied without holding it, so concurrent writers can share a sync.
+    fn write<T>(&self, op: impl FnOnce(&mut WriteAgent) -> Result<T>) -> Result<T> {
+        let (res, ticket) = {
+            let mut agent = self.write_agent.lock().unwrap();
+            let res = op(&mut agent)?;
+            (res, agent.ticket)
+        };
+        self.durability.wait(ticket)?;
+        Ok(res)
+    }
+}
+
+/// Reads the entries of `index` with keys in `range` that are live at `now`,
+/// at most `limit` of them.
+fn read_range(
+    index: &Index,
+    reader: &mut RecordReader,
+    range: impl RangeBounds<Vec<u8>>,
+    limit: Option<usize>,
+    now: u64,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if is_empty_range(&range) {
+        return Ok(Vec::new());
+    }
+    index
+        .range(range)
+        .filter(|(_, pos)| !is_expired(pos.expires_at, now))
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|(key, &pos)| Ok((key.clone(), reader.read_value(pos)?)))
+        .collect()
+}
+
+/// Rewrites `source`, laid out in format `version`, as segment `gen` in the
+/// current format. Version 0 is the single `.store` file from before segments.
+fn upgrade(
+    layout: &Layout,
+    options: &KvStoreOptions,
+    source: &Path,
+    version: u32,
+    gen: u64,
+) -> Result<()> {
+    info!(
+        "Upgrading {} from format version {} to {}",
+        source.display(),
+        version,
+        segment::FORMAT_VERSION
+    );
+    let mut file_reader = BufReader::with_capacity(options.read_buffer_size, File::open(source)?);
+    if version > 0 {
+        segment::read_header(&mut file_reader)?;
+    }
+    let mut writer = SegmentWriter::create_temp(layout, gen, options.write_buffer_size)?;
+    loop {
+        let offset = file_reader.stream_position()?;
+        let encode = |record: &Record| {
+            segment::encode_record(&record.key, record.value.as_deref(), record.expires_at)
+        };
+        match segment::read_record(&mut file_reader, version)? {
+            ReadResult::Record(record) => {
+                writer.append(&encode(&record))?;
+            }
+            ReadResult::Batch(records) => {
+                let records: Vec<_> = records.iter().map(encode).collect();
+                writer.append(&segment::encode_batch(&records))?;
+            }
+            ReadResult::End => break,
+            ReadResult::Truncated | ReadResult::Corrupt { .. } => {
+                return Err(From::from(KvError::Corrupt { gen, offset }))
+            }
+        }
+    }
+    writer.finish(layout)
+}
+
+/// Adds the records listed in the hint file of segment `gen` to `index`,
+/// returning the number of bytes they made stale.
+fn load_hint(gen: u64, entries: Vec<HintEntry>, index: &mut Index) -> u64 {
+    let now = now_millis();
+    let mut stale_bytes = 0;
+    for entry in entries {
+        let pos = CommandPos::new(gen, entry.pos, entry.len, entry.expires_at);
+        let old = if is_expired(pos.expires_at, now) {
+            stale_bytes += pos.len;
+            index.remove(&entry.key)
+        } else {
+            index.insert(entry.key, pos)
+        };
+        if let Some(old) = old {
+            stale_bytes += old.len;
+        }
+    }
+    stale_bytes
+}
+
+/// Replays the segment `gen` into `index`, returning the number of bytes it made stale.
+/// Sealed segments written by compaction are loaded from their hint file instead.
+///
+/// Only the active segment can legitimately end in a partial record, left by a
+/// crash in the middle of an append; that tail is cut off. Damage anywhere else
+/// is reported as [`KvError::Corrupt`].
+fn load_segment(
+    layout: &Layout,
+    options: &KvStoreOptions,
+    gen: u64,
+    index: &mut Index,
+    is_active: bool,
+) -> Result<u64> {
+    if !is_active {
+        if let Some(entries) = hint::read(layout, gen, options.read_buffer_size)? {
+            return Ok(load_hint(gen, entries, index));
+        }
+    }
+    let path = layout.segment(gen);
+    let file = File::open(&path)?;
+    let file_len = file.metadata()?.len();
+    let mut file_reader = BufReader::with_capacity(options.read_buffer_size, file);
+    let version = match segment::read_header(&mut file_reader)? {
+        Some(version) => version,
+        // the process died before the header of a fresh segment hit the disk
+        None if is_active => {
+            layout.truncate(gen, 0)?;
+            return Ok(0);
+        }
+        None => return Err(From::from(KvError::Corrupt { gen, offset: 0 })),
+    };
+    if version > segment::FORMAT_VERSION {
+        return Err(From::from(KvError::UnsupportedVersion(version)));
+    }
+    if version < segment::FORMAT_VERSION {
+        drop(file_reader);
+        // positions in the old hint file do not match the upgraded segment
+        hint::remove(layout, gen)?;
+        upgrade(layout, options, &path, version, gen)?;
+        return load_segment(layout, options, gen, index, is_active);
+    }
+
+    let now = now_millis();
+    let mut stale_bytes = 0;
+    let mut current_pos = segment::HEADER_LEN;
+    loop {
+        let (records, mut record_pos) = match segment::read_record(&mut file_reader, version)? {
+            ReadResult::Record(record) => (vec![record], current_pos),
+            ReadResult::Batch(records) => {
+                stale_bytes += segment::RECORD_HEADER_LEN;
+                (records, current_pos + segment::RECORD_HEADER_LEN)
+            }
+            ReadResult::End => break,
+            ReadResult::Truncated if is_active => {
+                warn!(
+                    "Truncating torn record in segment {} at offset {}",
+                    gen, current_pos
+                );
+                layout.truncate(gen, current_pos)?;
+                break;
+            }
+            ReadResult::Corrupt { len } if is_active && current_pos + len == file_len => {
+                warn!(
+                    "Truncating torn record in segment {} at offset {}",
+                    gen, current_pos
+                );
+                layout.truncate(gen, current_pos)?;
+                break;
+            }
+            ReadResult::Truncated | ReadResult::Corrupt { .. } => {
+                return Err(From::from(KvError::Corrupt {
+                    gen,
+                    offset: current_pos,
+                }))
+            }
+        };
+        for record in records {
+            let len = record.len();
+            let old = match record.value {
+                Some(_) if !is_expired(record.expires_at, now) => index.insert(
+                    record.key,
+                    CommandPos::new(gen, record_pos, len, record.expires_at),
+                ),
+                _ => {
+                    // the removal itself, like an expired value, is dead weight once the key is gone
+                    stale_bytes += len;
+                    index.remove(&record.key)
+                }
+            };
+            if let Some(old) = old {
+                stale_bytes += old.len;
+            }
+            record_pos += len;
+        }
+        current_pos = record_pos;
+    }
+    Ok(stale_bytes)
+}
+
+#[derive(Debug)]
+struct WriteAgent {
+    index: Arc<RwLock<Index>>,
+    layout: Layout,
+    options: Arc<KvStoreOptions>,
+    writer: SegmentWriter,
+    durability: Arc<Durability>,
+    /// Durability ticket of the latest append.
+    ticket: u64,
+    /// Version given to the next key written.
+    next_version: u64,
+    stale_bytes: u64,
+    live_bytes: u64,
+    /// Keys with an expiry time, soonest first.
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+    jobs: mpsc::Sender<Job>,
+    /// Set while a compaction is requested or running.
+    compacting: bool,
+    pins: Arc<Pins>,
+}
+
+impl WriteAgent {
+    /// Sets `key` to `value`, expiring at `expires_at` unless that is 0.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> crate::Result<()> {
+        let record = segment::encode_record(&key, Some(&value), expires_at);
+        let mut pos = self.append(&record)?;
+        pos.expires_at = expires_at;
+        let index = self.index.clone();
+        self.index_set(&mut index.write().unwrap(), key, pos);
+        self.maybe_compact();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) -> crate::Result<()> {
+        self.live(&key)?;
+        let record = segment::encode_record(&key, None, 0);
+        let pos = self.append(&record)?;
+        let index = self.index.clone();
+        self.index_remove(&mut index.write().unwrap(), &key, pos.len);
+        self.maybe_compact();
+        Ok(())
+    }
+
+    /// Appends the whole batch as one record, then applies it to the index
+    /// under a single lock so readers see all of it or none.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> crate::Result<()> {
+        let ops = batch.into_ops();
+        let records: Vec<Vec<u8>> = ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => segment::encode_record(key, Some(value), 0),
+                BatchOp::Remove { key } => segment::encode_record(key, None, 0),
+            })
+            .collect();
+        let batch_pos = self.append(&segment::encode_batch(&records))?;
+        self.stale_bytes += segment::RECORD_HEADER_LEN;
+
+        let index = self.index.clone();
+        let mut index = index.write().unwrap();
+        let mut pos = batch_pos.pos + segment::RECORD_HEADER_LEN;
+        for (op, record) in ops.into_iter().zip(records) {
+            let len = record.len() as u64;
+            match op {
+                BatchOp::Set { key, .. } => {
+                    self.index_set(&mut index, key, CommandPos::new(batch_pos.gen, pos, len, 0))
+                }
+                BatchOp::Remove { key } => self.index_remove(&mut index, &key, len),
+            }
+            pos += len;
+        }
+        drop(index);
+        self.maybe_compact();
+        Ok(())
+    }
+
+    /// Swaps the value of `key` for `new` if it currently is `expected`.
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> crate::Result<bool> {
+        if self.value(&key)? != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value, 0)?,
+            // a key expected to be missing has nothing to remove
+            None if expected.is_none() => {}
+            None => self.remove(key)?,
+        }
+        Ok(true)
+    }
+
+    pub fn set_if_present(&mut self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<bool> {
+        if self.live(&key).is_err() {
+            return Ok(false);
+        }
+        self.set(key, value, 0)?;
+        Ok(true)
+    }
+
+    /// Applies `batch` if every key in `reads` is still at the version given.
+    pub fn commit_if_unchanged(
+        &mut self,
+        reads: &BTreeMap<Vec<u8>, u64>,
+        batch: WriteBatch,
+    ) -> crate::Result<bool> {
+        let unchanged = {
+            let index = self.index.read().unwrap();
+            let now = now_millis();
+            reads.iter().all(|(key, &version)| {
+                let current = match index.get(key) {
+                    Some(pos) if !is_expired(pos.expires_at, now) => pos.version,
+                    _ => 0,
+                };
+                current == version
+            })
+        };
+        if unchanged && !batch.is_empty() {
+            self.write_batch(batch)?;
+        }
+        Ok(unchanged)
+    }
+
+    /// Adds `by` to the integer stored at `key`, keeping its expiry.
+    pub fn increment(&mut self, key: Vec<u8>, by: i64) -> crate::Result<i64> {
+        let (value, expires_at) = match self.entry(&key)? {
+            Some((value, expires_at)) => (Some(value), expires_at),
+            None => (None, 0),
+        };
+        let sum = add_to(value.as_deref(), by)?;
+        self.set(key, sum.to_string().into_bytes(), expires_at)?;
+        Ok(sum)
+    }
+
+    /// Appends `suffix` to the value of `key`, keeping its expiry.
+    pub fn append_value(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> crate::Result<usize> {
+        let (mut value, expires_at) = self.entry(&key)?.unwrap_or_default();
+        value.extend_from_slice(&suffix);
+        let len = value.len();
+        self.set(key, value, expires_at)?;
+        Ok(len)
+    }
+
+    /// Rewrites the value of `key` to expire at `expires_at`.
+    pub fn expire(&mut self, key: Vec<u8>, expires_at: u64) -> crate::Result<()> {
+        let value = self.value(&key)?.ok_or(KvError::KeyNotFound)?;
+        self.set(key, value, expires_at)
+    }
+
+    /// Drops every expired key from the index. Their records need no
+    /// tombstone since they are skipped on load anyway.
+    fn sweep(&mut self) {
+        let now = now_millis();
+        while let Some((expires_at, _)) = self.expiring.first() {
+            if !is_expired(*expires_at, now) {
+                break;
+            }
+            let (expires_at, key) = self.expiring.pop_first().unwrap();
+            let mut index = self.index.write().unwrap();
+            if let Some(pos) = index.get(&key) {
+                if pos.expires_at == expires_at {
+                    let len = pos.len;
+                    index.remove(&key);
+                    self.stale_bytes += len;
+                    self.live_bytes -= len;
+                }
+            }
+        }
+        self.maybe_compact();
+    }
+
+    /// Position of the live record of `key`, failing if it is missing or expired.
+    fn live(&self, key: &[u8]) -> Result<CommandPos> {
+        match self.index.read().unwrap().get(key) {
+            Some(&pos) if !is_expired(pos.expires_at, now_millis()) => Ok(pos),
+            _ => Err(From::from(KvError::KeyNotFound)),
+        }
+    }
+
+    /// Current value of `key`, `None` if it is missing or expired.
+    fn value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entry(key)?.map(|(value, _)| value))
+    }
+
+    /// Current value of `key` along with its expiry time, `None` if it is missing or expired.
+    fn entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        // holding the index keeps compaction from deleting the segment being read
+        let index = self.index.read().unwrap();
+        match index.get(key) {
+            Some(&pos) if !is_expired(pos.expires_at, now_millis()) => {
+                let mut reader = RecordReader {
+                    layout: &self.layout,
+                    buffer_size: self.options.read_buffer_size,
+                    segment: None,
+                };
+                Ok(Some((reader.read_value(pos)?, pos.expires_at)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Points `key` at its new record `pos`, moving it to a new version.
+    fn index_set(&mut self, index: &mut Index, key: Vec<u8>, mut pos: CommandPos) {
+        pos.version = self.next_version;
+        self.next_version += 1;
+        self.live_bytes += pos.len;
+        if let Some(old) = index.insert(key.clone(), pos) {
+            self.forget(&key, old);
+        }
+        if pos.expires_at != 0 {
+            self.expiring.insert((pos.expires_at, key));
+        }
+    }
+
+    /// Drops `key`, removed by a tombstone of `len` bytes.
+    fn index_remove(&mut self, index: &mut Index, key: &[u8], len: u64) {
+        // the removal itself is dead weight once the key is gone
+        self.stale_bytes += len;
+        if let Some(old) = index.remove(key) {
+            self.forget(key, old);
+        }
+    }
+
+    /// Accounts for `old`, the record of `key`, having been superseded.
+    fn forget(&mut self, key: &[u8], old: CommandPos) {
+        self.stale_bytes += old.len;
+        self.live_bytes -= old.len;
+        if old.expires_at != 0 {
+            self.expiring.remove(&(old.expires_at, key.to_vec()));
+        }
+    }
+
+    /// Asks the compactor for a run once enough of the log is stale.
+    fn maybe_compact(&mut self) {
+        if !self.compacting
+            && self
+                .options
+                .should_compact(self.stale_bytes, self.live_bytes)
+        {
+            self.compacting = true;
+            let _ = self.jobs.send(Job::Compact);
+        }
+    }
+
+    /// Appends an encoded record to the active segment, moving on to a fresh
+    /// segment first if the active one is full.
+    fn append(&mut self, record: &[u8]) -> Result<CommandPos> {
+        if self.writer.len >= self.options.max_segment_size {
+            self.switch_segment(self.writer.gen + 1)?;
+        }
+        let pos = self.writer.append(record)?;
+        self.ticket = self.durability.appended(&mut self.writer)?;
+        Ok(CommandPos::new(
+            self.writer.gen,
+            pos,
+            record.len() as u64,
+            0,
+        ))
+    }
+
+    /// Moves appends on to segment `gen`.
+    fn switch_segment(&mut self, gen: u64) -> Result<()> {
+        let writer = SegmentWriter::open(&self.layout, gen, self.options.write_buffer_size)?;
+        let mut old = mem::replace(&mut self.writer, writer);
+        self.durability.switched(&mut old, &self.writer)
+    }
+}
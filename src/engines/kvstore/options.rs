@@ -0,0 +1,126 @@
+use super::KvStore;
+use crate::{engines::SyncPolicy, Result};
+use std::{path::Path, time::Duration};
+
+/// When the background compactor rewrites the log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionTrigger {
+    /// Once at least this many bytes of the log are stale.
+    StaleBytes(u64),
+    /// Once stale bytes make up at least this fraction (0.0 to 1.0) of the log.
+    StaleRatio(f64),
+}
+
+/// Tuning for a [`KvStore`], passed to [`KvStore::open_with_options`].
+///
+/// ```no_run
+/// # use trash_db::engines::kvstore::{CompactionTrigger, KvStoreOptions};
+/// # fn main() -> trash_db::Result<()> {
+/// let store = KvStoreOptions::new()
+///     .compaction(CompactionTrigger::StaleRatio(0.5))
+///     .max_segment_size(64 * 1024 * 1024)
+///     .open(std::path::Path::new("data"))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) compaction: CompactionTrigger,
+    pub(super) max_segment_size: u64,
+    pub(super) file_name: String,
+    pub(super) read_buffer_size: usize,
+    pub(super) write_buffer_size: usize,
+    pub(super) sync: SyncPolicy,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction: CompactionTrigger::StaleBytes(1024 * 1024),
+            max_segment_size: 1024 * 1024,
+            file_name: ".store".to_owned(),
+            read_buffer_size: 8 * 1024,
+            write_buffer_size: 8 * 1024,
+            sync: SyncPolicy::Never,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// When to compact; defaults to 1 MiB of stale data.
+    pub fn compaction(mut self, trigger: CompactionTrigger) -> Self {
+        self.compaction = trigger;
+        self
+    }
+
+    /// Size at which the active segment is sealed and a new one started; defaults to 1 MiB.
+    pub fn max_segment_size(mut self, bytes: u64) -> Self {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// Base name of the data files; segments are named `<file_name>.<generation>`.
+    /// Defaults to `.store`.
+    pub fn file_name(mut self, name: impl Into<String>) -> Self {
+        self.file_name = name.into();
+        self
+    }
+
+    /// Buffer size used when reading segments and hint files.
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// Buffer size used when appending to segments.
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.write_buffer_size = bytes;
+        self
+    }
+
+    /// When writes are synced to disk; defaults to [`SyncPolicy::Never`].
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    /// Opens the store in `path` with these options.
+    pub fn open(self, path: &Path) -> Result<KvStore> {
+        KvStore::open_with_options(path, self)
+    }
+
+    pub(super) fn validate(&self) -> Result<()> {
+        if self.file_name.is_empty() || self.file_name.contains(['/', '\\']) {
+            return Err(From::from(format!(
+                "Invalid file name {:?}",
+                self.file_name
+            )));
+        }
+        if self.max_segment_size == 0 {
+            return Err(From::from("Segment size must be positive"));
+        }
+        if self.sync == SyncPolicy::Interval(Duration::ZERO) {
+            return Err(From::from("Sync interval must be positive"));
+        }
+        if let CompactionTrigger::StaleRatio(ratio) = self.compaction {
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err(From::from(format!("Invalid stale ratio {}", ratio)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a log with this much stale and live data should be compacted.
+    pub(super) fn should_compact(&self, stale_bytes: u64, live_bytes: u64) -> bool {
+        match self.compaction {
+            CompactionTrigger::StaleBytes(threshold) => stale_bytes >= threshold,
+            CompactionTrigger::StaleRatio(ratio) => {
+                stale_bytes > 0 && stale_bytes as f64 >= ratio * (stale_bytes + live_bytes) as f64
+            }
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use crate::{KvError, Result};
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+const TEMP_SUFFIX: &str = "compacting";
+
+/// Every segment starts with `MAGIC | version`.
+const MAGIC: [u8; 4] = *b"TRDB";
+pub(super) const HEADER_LEN: u64 = 4 + 4;
+/// Version of the segment layout written by this build.
+/// Version 0 is the headerless single `.store` file that predates segments,
+/// version 1 has no expiry times and version 2 no header checksum.
+pub(super) const FORMAT_VERSION: u32 = 3;
+
+/// `crc | header_crc | kind | expires_at | key_len | value_len`, the checksum
+/// covering everything after itself and the header checksum the fields after
+/// it, so a damaged length is caught before it is used.
+pub(super) const RECORD_HEADER_LEN: u64 = 4 + 4 + 1 + 8 + 4 + 4;
+/// `crc | kind | expires_at | key_len | value_len` in version 2.
+const V2_RECORD_HEADER_LEN: u64 = 4 + 1 + 8 + 4 + 4;
+/// `crc | kind | key_len | value_len` in version 1.
+const V1_RECORD_HEADER_LEN: u64 = 4 + 1 + 4 + 4;
+const KIND_SET: u8 = 1;
+const KIND_REMOVE: u8 = 2;
+/// A write batch: the value holds complete `set` and removal records, so a
+/// torn batch fails the outer checksum as a whole.
+const KIND_BATCH: u8 = 3;
+
+/// A single entry of a segment: a `set` when `value` is present, a removal otherwise.
+#[derive(Debug)]
+pub(super) struct Record {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    /// When a `set` expires, in milliseconds since the Unix epoch; 0 for never.
+    pub expires_at: u64,
+}
+
+impl Record {
+    /// Size of the record on disk, header included.
+    pub fn len(&self) -> u64 {
+        encoded_len(&self.key, self.value.as_deref())
+    }
+
+    /// Size the record had in a segment laid out in format `version`.
+    pub fn len_in(&self, version: u32) -> u64 {
+        self.len() - RECORD_HEADER_LEN + record_header_len(version)
+    }
+}
+
+/// Outcome of reading the next record of a segment.
+#[derive(Debug)]
+pub(super) enum ReadResult {
+    Record(Record),
+    /// The records of a write batch, which follow the batch header back to back.
+    Batch(Vec<Record>),
+    /// Nothing left to read.
+    End,
+    /// The segment ends in the middle of a record.
+    Truncated,
+    /// A complete record of `len` bytes whose checksum does not match, or a
+    /// damaged header of `len` bytes whose lengths cannot be trusted.
+    Corrupt {
+        len: u64,
+    },
+}
+
+pub(super) fn encoded_len(key: &[u8], value: Option<&[u8]>) -> u64 {
+    RECORD_HEADER_LEN + key.len() as u64 + value.map_or(0, |v| v.len() as u64)
+}
+
+pub(super) fn encode_record(key: &[u8], value: Option<&[u8]>, expires_at: u64) -> Vec<u8> {
+    let kind = match value {
+        Some(_) => KIND_SET,
+        None => KIND_REMOVE,
+    };
+    encode(kind, key, value.unwrap_or_default(), expires_at)
+}
+
+/// Wraps encoded records into a single batch record.
+pub(super) fn encode_batch(records: &[Vec<u8>]) -> Vec<u8> {
+    encode(KIND_BATCH, &[], &records.concat(), 0)
+}
+
+fn encode(kind: u8, key: &[u8], value: &[u8], expires_at: u64) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_LEN as usize + key.len() + value.len());
+    bytes.extend_from_slice(&[0u8; 8]);
+    bytes.push(kind);
+    bytes.extend_from_slice(&expires_at.to_le_bytes());
+    bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    let header_crc = crc32fast::hash(&bytes[8..]);
+    bytes[4..8].copy_from_slice(&header_crc.to_le_bytes());
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(value);
+    let crc = crc32fast::hash(&bytes[4..]);
+    bytes[..4].copy_from_slice(&crc.to_le_bytes());
+    bytes
+}
+
+/// The header every segment, and every hint file, starts with.
+pub(super) fn header_bytes() -> [u8; HEADER_LEN as usize] {
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Reads the segment header, returning the format version it declares.
+/// Returns `None` for a segment too short to hold a header.
+pub(super) fn read_header(reader: &mut impl Read) -> Result<Option<u32>> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    if read_full(reader, &mut header)? < header.len() {
+        return Ok(None);
+    }
+    if header[..4] != MAGIC {
+        return Err(From::from(KvError::UnknownFormat));
+    }
+    Ok(Some(u32::from_le_bytes([
+        header[4], header[5], header[6], header[7],
+    ])))
+}
+
+/// Reads the record at the current position of `reader`, laid out in format `version`.
+pub(super) fn read_record(reader: &mut impl Read, version: u32) -> Result<ReadResult> {
+    match version {
+        0 => read_legacy_record(reader),
+        1..=FORMAT_VERSION => read_checked_record(reader, version),
+        version => Err(From::from(KvError::UnsupportedVersion(version))),
+    }
+}
+
+/// Size of a record header in format `version`, which has checksums.
+pub(super) fn record_header_len(version: u32) -> u64 {
+    match version {
+        1 => V1_RECORD_HEADER_LEN,
+        2 => V2_RECORD_HEADER_LEN,
+        _ => RECORD_HEADER_LEN,
+    }
+}
+
+/// Reads a record with a checksum, laid out in format `version`.
+fn read_checked_record(reader: &mut impl Read, version: u32) -> Result<ReadResult> {
+    let header_len = record_header_len(version);
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    let header = &mut header[..header_len as usize];
+    match read_full(reader, header)? {
+        0 => return Ok(ReadResult::End),
+        n if n < header.len() => return Ok(ReadResult::Truncated),
+        _ => {}
+    }
+    let crc = u32::from_le_bytes(header[..4].try_into()?);
+    // the fields start after the header checksum, where there is one
+    let fields = match version {
+        1 | 2 => 4,
+        _ => {
+            let header_crc = u32::from_le_bytes(header[4..8].try_into()?);
+            if crc32fast::hash(&header[8..]) != header_crc {
+                return Ok(ReadResult::Corrupt { len: header_len });
+            }
+            8
+        }
+    };
+    let kind = header[fields];
+    let expires_at = match version {
+        1 => 0,
+        _ => u64::from_le_bytes(header[fields + 1..fields + 9].try_into()?),
+    };
+    let lengths = &header[header.len() - 8..];
+    let key_length = u32::from_le_bytes(lengths[..4].try_into()?) as u64;
+    let value_length = u32::from_le_bytes(lengths[4..].try_into()?) as u64;
+
+    // read through `take` so a damaged length cannot make us allocate gigabytes up front
+    let mut body = Vec::new();
+    reader
+        .take(key_length + value_length)
+        .read_to_end(&mut body)?;
+    if (body.len() as u64) < key_length + value_length {
+        return Ok(ReadResult::Truncated);
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(&body);
+    let len = header_len + body.len() as u64;
+    if hasher.finalize() != crc {
+        return Ok(ReadResult::Corrupt { len });
+    }
+
+    let value = body.split_off(key_length as usize);
+    let key = body;
+    let value = match kind {
+        KIND_SET => Some(value),
+        KIND_REMOVE => None,
+        KIND_BATCH if version > 1 => {
+            return Ok(read_batch(&value, version).unwrap_or(ReadResult::Corrupt { len }))
+        }
+        _ => return Ok(ReadResult::Corrupt { len }),
+    };
+    Ok(ReadResult::Record(Record {
+        key,
+        value,
+        expires_at,
+    }))
+}
+
+/// Splits the value of a batch record into its records, or `None` if it does
+/// not hold complete `set` and removal records only.
+fn read_batch(mut bytes: &[u8], version: u32) -> Option<ReadResult> {
+    let mut records = Vec::new();
+    loop {
+        match read_checked_record(&mut bytes, version).ok()? {
+            ReadResult::Record(record) => records.push(record),
+            ReadResult::End => return Some(ReadResult::Batch(records)),
+            _ => return None,
+        }
+    }
+}
+
+/// Reads a record in the version 0 layout, which had no checksums:
+/// `key_len | value_len | key | value`, where an empty value marks a removal.
+fn read_legacy_record(reader: &mut impl Read) -> Result<ReadResult> {
+    let chunk = &mut [0u8; 8];
+    match read_full(reader, chunk)? {
+        0 => return Ok(ReadResult::End),
+        n if n < chunk.len() => return Ok(ReadResult::Truncated),
+        _ => {}
+    }
+    let key_length = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64;
+    let value_length = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+    let mut body = Vec::new();
+    reader
+        .take(key_length + value_length)
+        .read_to_end(&mut body)?;
+    if (body.len() as u64) < key_length + value_length {
+        return Ok(ReadResult::Truncated);
+    }
+    let value = body.split_off(key_length as usize);
+    let key = body;
+    let value = match value_length {
+        0 => None,
+        _ => Some(value),
+    };
+    Ok(ReadResult::Record(Record {
+        key,
+        value,
+        expires_at: 0,
+    }))
+}
+
+/// Like `read_exact`, but reports how much was read instead of failing at end of file.
+pub(super) fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Names of the files a store keeps in its data directory: segments are
+/// `<name>.<gen>`, their hint files `<name>.<gen>.hint`.
+#[derive(Debug, Clone)]
+pub(super) struct Layout {
+    dir: PathBuf,
+    name: String,
+}
+
+impl Layout {
+    pub fn new(dir: &Path, name: &str) -> Self {
+        Layout {
+            dir: dir.to_path_buf(),
+            name: name.to_owned(),
+        }
+    }
+
+    /// The single data file used before the log was split into segments.
+    pub fn legacy(&self) -> PathBuf {
+        self.dir.join(&self.name)
+    }
+
+    fn file(&self, suffix: impl Display) -> PathBuf {
+        self.dir.join(format!("{}.{}", self.name, suffix))
+    }
+
+    pub fn segment(&self, gen: u64) -> PathBuf {
+        self.file(gen)
+    }
+
+    pub fn hint(&self, gen: u64) -> PathBuf {
+        self.file(format_args!("{}.hint", gen))
+    }
+
+    pub fn lock_file(&self) -> PathBuf {
+        self.file("lock")
+    }
+
+    /// Takes the advisory lock that keeps other processes out of the store.
+    /// It is held until the returned file is closed.
+    pub fn lock(&self) -> Result<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.lock_file())?;
+        match file.try_lock() {
+            Ok(()) => Ok(file),
+            Err(TryLockError::WouldBlock) => Err(From::from(KvError::Locked)),
+            Err(TryLockError::Error(e)) => Err(Box::new(e)),
+        }
+    }
+
+    /// Where `path` is written to until it is complete.
+    pub fn temp(path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(".");
+        name.push(TEMP_SUFFIX);
+        path.with_file_name(name)
+    }
+
+    /// Generations of all segments, oldest first.
+    pub fn sorted_gens(&self) -> Result<Vec<u64>> {
+        let prefix = format!("{}.", self.name);
+        let mut gens: Vec<u64> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_prefix(&prefix))
+                    .and_then(|gen| gen.parse().ok())
+            })
+            .collect();
+        gens.sort_unstable();
+        Ok(gens)
+    }
+
+    /// Removes files that were still being written when the process died.
+    pub fn remove_temp_files(&self) -> Result<()> {
+        let prefix = format!("{}.", self.name);
+        let suffix = format!(".{}", TEMP_SUFFIX);
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(&prefix) && name.ends_with(&suffix) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forces files created, renamed or deleted in the data directory to disk.
+    pub fn sync_dir(&self) -> io::Result<()> {
+        sync_dir(&self.dir)
+    }
+
+    /// Cuts the segment `gen` down to `len` bytes.
+    pub fn truncate(&self, gen: u64, len: u64) -> Result<()> {
+        let file = OpenOptions::new().write(true).open(self.segment(gen))?;
+        file.set_len(len)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Appending writer over one segment that keeps track of its own length.
+#[derive(Debug)]
+pub(super) struct SegmentWriter {
+    pub gen: u64,
+    pub len: u64,
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl SegmentWriter {
+    pub fn open(layout: &Layout, gen: u64, buffer_size: usize) -> Result<Self> {
+        Self::open_at(layout.segment(gen), gen, buffer_size)
+    }
+
+    /// Starts a segment under a temporary name; it only shows up as segment
+    /// `gen` once [`SegmentWriter::finish`] succeeds.
+    pub fn create_temp(layout: &Layout, gen: u64, buffer_size: usize) -> Result<Self> {
+        let path = Layout::temp(&layout.segment(gen));
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Self::open_at(path, gen, buffer_size)
+    }
+
+    fn open_at(path: PathBuf, gen: u64, buffer_size: usize) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let mut writer = SegmentWriter {
+            gen,
+            len,
+            path,
+            writer: BufWriter::with_capacity(buffer_size, file),
+        };
+        if len == 0 {
+            writer.append(&header_bytes())?;
+            writer.flush()?;
+            // syncing the file alone would not keep its name
+            sync_dir(writer.path.parent().unwrap_or(Path::new(".")))?;
+        }
+        Ok(writer)
+    }
+
+    /// Appends `bytes` and returns the offset they were written at.
+    pub fn append(&mut self, bytes: &[u8]) -> io::Result<u64> {
+        let pos = self.len;
+        self.writer.write_all(bytes)?;
+        self.len += bytes.len() as u64;
+        Ok(pos)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Flushes and forces everything appended so far to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+
+    /// A second handle on the file, for syncing it from elsewhere.
+    pub fn sync_handle(&self) -> io::Result<File> {
+        self.writer.get_ref().try_clone()
+    }
+
+    /// Syncs a segment started with [`SegmentWriter::create_temp`] and moves it into place.
+    pub fn finish(mut self, layout: &Layout) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        fs::rename(&self.path, layout.segment(self.gen))?;
+        layout.sync_dir()?;
+        Ok(())
+    }
+}
+
+/// Syncs the entries of directory `dir`, which holds the names of its files.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    // only Unix lets a directory be opened and synced like a file
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use super::{
+    compaction, hint, read_range, Index, KvStoreOptions, Layout, RecordReader, SegmentWriter,
+};
+use crate::{
+    engines::{is_expired, prefix_end},
+    KvError, Result,
+};
+use log::error;
+use std::{
+    fs::{self, File},
+    mem,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// A read-only view of a [`KvStore`] frozen at one position in the log.
+/// Writes made after [`KvStore::snapshot`] returned are not visible, and
+/// keys are expired as of the moment it was taken.
+///
+/// The segments a snapshot reads from outlive compaction until it is dropped.
+///
+/// [`KvStore`]: super::KvStore
+/// [`KvStore::snapshot`]: super::KvStore::snapshot
+#[derive(Debug)]
+pub struct Snapshot {
+    pub(super) index: Index,
+    pub(super) layout: Layout,
+    pub(super) options: Arc<KvStoreOptions>,
+    /// When the snapshot was taken, in milliseconds since the Unix epoch.
+    pub(super) taken_at: u64,
+    /// Generation and length of the active segment when the snapshot was taken.
+    pub(super) position: (u64, u64),
+    pub(super) pins: Arc<Pins>,
+    /// Keeps other processes out while the snapshot is read.
+    pub(super) _lock: Arc<File>,
+}
+
+impl Snapshot {
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&key) {
+            Some(&pos) if !is_expired(pos.expires_at, self.taken_at) => {
+                self.reader().read_value(pos).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Fails if the stored value is not valid UTF-8.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Entries with keys in `range` in key order, at most `limit` of them.
+    pub fn scan(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        read_range(&self.index, &mut self.reader(), range, limit, self.taken_at)
+    }
+
+    /// All entries whose key starts with `prefix`, in key order.
+    pub fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = prefix_end(&prefix);
+        self.scan((Bound::Included(prefix), end), None)
+    }
+
+    /// Number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.index
+            .values()
+            .filter(|pos| !is_expired(pos.expires_at, self.taken_at))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Generation of the active segment and the offset in it that the
+    /// snapshot was taken at. Every record it reads lies before that point.
+    pub fn position(&self) -> (u64, u64) {
+        self.position
+    }
+
+    /// Writes the snapshot into `dest` as a freshly compacted store, which
+    /// can be opened like any other. `dest` must not hold a store yet.
+    pub fn backup(&self, dest: &Path) -> Result<()> {
+        write_store(
+            &self.layout,
+            dest,
+            &self.options,
+            &self.index,
+            self.taken_at,
+        )
+    }
+
+    fn reader(&self) -> RecordReader<'_> {
+        RecordReader {
+            layout: &self.layout,
+            buffer_size: self.options.read_buffer_size,
+            segment: None,
+        }
+    }
+}
+
+/// Writes the keys of `index`, which point into the segments of `source`,
+/// into `dest` as a freshly compacted store, leaving out those expired by
+/// `now`. `dest` must not hold a store yet.
+pub(super) fn write_store(
+    source: &Layout,
+    dest: &Path,
+    options: &KvStoreOptions,
+    index: &Index,
+    now: u64,
+) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    let target = Layout::new(dest, &options.file_name);
+    let _lock = target.lock()?;
+    if !target.sorted_gens()?.is_empty() || target.legacy().exists() {
+        return Err(From::from(KvError::AlreadyExists(dest.to_path_buf())));
+    }
+    let mut entries: Vec<_> = index
+        .iter()
+        .filter(|(_, pos)| !is_expired(pos.expires_at, now))
+        .map(|(key, pos)| (key.clone(), *pos))
+        .collect();
+    entries.sort_unstable_by_key(|(_, pos)| (pos.gen, pos.pos));
+    let moved = compaction::copy_into(source, &target, options, &entries, 1..=u64::MAX)?;
+    // writes go to a fresh segment, so the hint files of the copies stay true
+    let last_gen = moved.last().map_or(1, |(_, _, pos)| pos.gen);
+    SegmentWriter::create_temp(&target, last_gen + 1, options.write_buffer_size)?
+        .finish(&target)?;
+    Ok(())
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(e) = self.pins.unpin(&self.layout) {
+            error!("Deleting compacted segments failed: {}", e);
+        }
+    }
+}
+
+/// Counts the open snapshots, holding back the deletion of compacted
+/// segments until none is left that could still read them.
+#[derive(Debug, Default)]
+pub(super) struct Pins {
+    state: Mutex<PinState>,
+}
+
+#[derive(Debug, Default)]
+struct PinState {
+    snapshots: usize,
+    /// Segments replaced by compaction, waiting for the snapshots to go.
+    retired: Vec<u64>,
+}
+
+impl Pins {
+    pub fn pin(&self) {
+        self.state.lock().unwrap().snapshots += 1;
+    }
+
+    pub fn unpin(&self, layout: &Layout) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.snapshots -= 1;
+        if state.snapshots == 0 {
+            let retired = mem::take(&mut state.retired);
+            delete_segments(layout, &retired)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the segments `gens`, or leaves that to the last open snapshot.
+    pub fn retire(&self, layout: &Layout, gens: Vec<u64>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.snapshots > 0 {
+            // a later compaction finds the segments still there
+            for gen in gens {
+                if !state.retired.contains(&gen) {
+                    state.retired.push(gen);
+                }
+            }
+            return Ok(());
+        }
+        delete_segments(layout, &gens)
+    }
+}
+
+fn delete_segments(layout: &Layout, gens: &[u64]) -> Result<()> {
+    for &gen in gens {
+        // without its hint a half-deleted segment is simply scanned again
+        hint::remove(layout, gen)?;
+        fs::remove_file(layout.segment(gen))?;
+    }
+    layout.sync_dir()?;
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use super::{KvsEngine, WriteBatch};
+use crate::{KvError, Result};
+use std::{ops::Bound, time::Duration};
+
+/// How many keys are read and written at a time.
+pub(super) const PAGE_SIZE: usize = 1000;
+
+/// A key, its value and the time it has left to live.
+pub(super) type Entry = (Vec<u8>, Vec<u8>, Option<Duration>);
+
+/// Copies every key of `from` into `to`, expiry times included, and returns
+/// how many were copied. Keys already in `to` are overwritten.
+///
+/// Keys are copied a page at a time, so `from` should not be written to
+/// meanwhile; writes that race with the copy may or may not make it.
+pub fn migrate(from: &impl KvsEngine, to: &impl KvsEngine) -> Result<u64> {
+    let mut count = 0;
+    for_each_page(from, |page| {
+        count += page.len() as u64;
+        write_page(to, page)
+    })?;
+    Ok(count)
+}
+
+/// Calls `f` with every key of `engine` in key order, [`PAGE_SIZE`] at a time.
+pub(super) fn for_each_page(
+    engine: &impl KvsEngine,
+    mut f: impl FnMut(Vec<Entry>) -> Result<()>,
+) -> Result<()> {
+    let mut start = Bound::Unbounded;
+    loop {
+        let page = engine.scan((start, Bound::Unbounded), Some(PAGE_SIZE))?;
+        let Some((last, _)) = page.last() else {
+            return Ok(());
+        };
+        start = Bound::Excluded(last.clone());
+        let mut entries = Vec::with_capacity(page.len());
+        for (key, value) in page {
+            match engine.ttl(key.clone()) {
+                Ok(ttl) => entries.push((key, value, ttl)),
+                // expired since it was scanned
+                Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyNotFound)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        f(entries)?;
+    }
+}
+
+/// Writes `entries` to `engine`, the ones that never expire in one batch.
+pub(super) fn write_page(engine: &impl KvsEngine, entries: Vec<Entry>) -> Result<()> {
+    let mut batch = WriteBatch::new();
+    let mut expiring = Vec::new();
+    for (key, value, ttl) in entries {
+        match ttl {
+            Some(ttl) => expiring.push((key, value, ttl)),
+            None => {
+                batch.set(key, value);
+            }
+        }
+    }
+    engine.write_batch(batch)?;
+    for (key, value, ttl) in expiring {
+        engine.set_with_ttl(key, value, ttl)?;
+    }
+    Ok(())
+}
@@ -1,13 +1,195 @@
-use crate::Result;
+use crate::{KvError, Result};
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    str,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+mod batch;
+mod export;
+mod group_commit;
 pub mod kvstore;
+mod migrate;
+mod periodic;
 pub mod sled;
+mod transaction;
 
+pub use batch::{BatchOp, WriteBatch};
+pub use export::{export, import, Format};
+pub use migrate::migrate;
+pub use transaction::Transaction;
+
+/// How often expired keys are swept out of the engines.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When an engine forces acknowledged writes to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never sync explicitly and leave it to the OS (or sled's own flushing).
+    Never,
+    /// Sync after every write, before it is acknowledged.
+    Always,
+    /// Sync before acknowledging, sharing one sync between concurrent writers.
+    GroupCommit,
+    /// Sync in the background at this interval; a crash can lose the last one.
+    Interval(Duration),
+}
+
+/// A key-value store. Keys and values are arbitrary bytes; `set`, `get` and
+/// `remove` are conveniences for UTF-8 strings on top of the byte methods.
 pub trait KvsEngine
 where
     Self: Send + Clone + 'static,
 {
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    /// Short name of the engine, as reported to clients.
+    fn name(&self) -> &'static str;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+    /// Applies all of `batch` atomically.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Starts a transaction over several keys.
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+    /// Value of `key` along with its version, 0 for a missing key. Any write
+    /// of the key moves it to a different version.
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)>;
+    /// Applies `batch` atomically if every key in `reads` is still at the
+    /// version given, returning whether it did.
+    fn commit_if_unchanged(
+        &self,
+        reads: &BTreeMap<Vec<u8>, u64>,
+        batch: WriteBatch,
+    ) -> Result<bool>;
+
+    /// Replaces the value of `key` with `new` if it currently is `expected`,
+    /// `None` standing for a missing key on either side. Returns whether it did.
+    /// Like `set`, a swap clears any expiry.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+    /// Sets `key` only if it is missing, returning whether it did.
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+    /// Sets `key` only if it exists, returning whether it did.
+    fn set_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool>;
+
+    /// Adds `by` to the integer stored at `key` as decimal text, a missing key
+    /// counting as 0, and returns the result. Any expiry is kept.
+    fn increment(&self, key: Vec<u8>, by: i64) -> Result<i64>;
+    /// Subtracts `by` from the integer stored at `key`, like [`KvsEngine::increment`].
+    fn decrement(&self, key: Vec<u8>, by: i64) -> Result<i64> {
+        self.increment(key, by.checked_neg().ok_or(KvError::Overflow)?)
+    }
+    /// Appends `suffix` to the value of `key`, a missing key counting as empty,
+    /// and returns the new length. Any expiry is kept.
+    fn append(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize>;
+
+    /// Sets `key` to `value` until `ttl` has passed, after which it is gone.
+    /// A plain `set` clears the expiry again.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    /// Makes an existing key expire once `ttl` has passed.
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<()>;
+    /// Time left until `key` expires, `None` if it never does.
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// Writes a copy of the data into `dest` while the engine stays in use.
+    /// kvs copies the data as of one instant; sled copies each key together
+    /// with its expiry, but not all keys at the same instant. `dest` must not
+    /// hold a store yet; the copy can be opened as one, or restored into a
+    /// fresh data directory.
+    fn backup(&self, dest: &Path) -> Result<()>;
+
+    /// Entries with keys in `range` in key order, at most `limit` of them.
+    fn scan(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// All entries whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = prefix_end(&prefix);
+        self.scan((Bound::Included(prefix), end), None)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+    /// Fails if the stored value is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+}
+
+/// The first key after every key starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// Whether `range` cannot contain any key. Ordered maps panic on such ranges.
+fn is_empty_range(range: &impl RangeBounds<Vec<u8>>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// `value`, an integer in decimal text or 0 when missing, plus `by`.
+fn add_to(value: Option<&[u8]>, by: i64) -> std::result::Result<i64, KvError> {
+    let current = match value {
+        Some(value) => str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or(KvError::NotAnInteger)?,
+        None => 0,
+    };
+    current.checked_add(by).ok_or(KvError::Overflow)
+}
+
+/// Milliseconds since the Unix epoch, the unit expiry times are kept in.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Expiry time of an entry set now with `ttl`. Never 0, which stands for no expiry.
+fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64).max(1)
+}
+
+/// Whether an entry with this expiry time is gone by `now`.
+fn is_expired(expires_at: u64, now: u64) -> bool {
+    expires_at != 0 && expires_at <= now
+}
+
+/// Time left until `expires_at`, `None` for entries that never expire.
+fn time_left(expires_at: u64) -> Option<Duration> {
+    (expires_at != 0).then(|| Duration::from_millis(expires_at.saturating_sub(now_millis())))
 }
@@ -0,0 +1,47 @@
+use crate::Result;
+use std::{
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Handle to a thread running a task at a fixed interval.
+/// Dropping it runs the task one last time and waits for the thread to exit.
+#[derive(Debug)]
+pub(crate) struct Periodic {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    pub fn spawn(
+        name: &str,
+        interval: Duration,
+        mut task: impl FnMut() + Send + 'static,
+    ) -> Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || loop {
+                let stopping =
+                    stopped.recv_timeout(interval) != Err(mpsc::RecvTimeoutError::Timeout);
+                task();
+                if stopping {
+                    break;
+                }
+            })?;
+        Ok(Periodic {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
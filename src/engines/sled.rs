@@ -1,37 +1,332 @@
-use super::KvsEngine;
+use super::{
+    add_to,
+    batch::{BatchOp, WriteBatch},
+    expiry_after,
+    group_commit::GroupCommit,
+    is_empty_range, is_expired, now_millis,
+    periodic::Periodic,
+    time_left, KvsEngine, SyncPolicy, SWEEP_INTERVAL,
+};
 use crate::KvError;
-use sled::Db;
-use std::{env, path::PathBuf};
+use log::error;
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree,
+    },
+    Db, IVec, Transactional, Tree,
+};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 #[derive(Clone, Debug)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    trees: Trees,
+    sync: SyncPolicy,
+    group: Arc<GroupCommit>,
+    /// Only held so sweeping stops with the last handle.
+    _sweeper: Arc<Periodic>,
+}
+
+/// The data along with the trees kept about its keys.
+#[derive(Clone, Debug)]
+struct Trees {
+    db: Db,
+    /// Expiry times of the keys that have one, big-endian.
+    ttl: Tree,
+    /// Versions of the keys written since versions were kept, big-endian.
+    versions: Tree,
+}
 
 impl KvsEngine for SledKvsEngine {
-    fn get(&self, key: String) -> crate::Result<Option<String>> {
-        let tree = &self.0;
-        Ok(tree
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
-    }
-    fn set(&self, key: String, value: String) -> crate::Result<()> {
-        let tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
-        tree.flush()?;
-        Ok(())
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+    fn get_bytes(&self, key: Vec<u8>) -> crate::Result<Option<Vec<u8>>> {
+        let value = self.trees.transaction(|tx| tx.unexpired(&key))?;
+        Ok(value.map(|i_vec| i_vec.to_vec()))
+    }
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<()> {
+        self.trees.transaction(|tx| {
+            tx.insert(&key, &value)?;
+            tx.ttl.remove(key.as_slice())?;
+            Ok(())
+        })?;
+        self.commit()
+    }
+    fn remove_bytes(&self, key: Vec<u8>) -> crate::Result<()> {
+        self.trees.transaction(|tx| {
+            let expires_at = expiry(tx.ttl.get(&key)?);
+            if tx.remove(&key)?.is_none() || is_expired(expires_at, now_millis()) {
+                return Err(ConflictableTransactionError::Abort(KvError::KeyNotFound));
+            }
+            Ok(())
+        })?;
+        self.commit()
+    }
+    fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        let ops = batch.into_ops();
+        self.trees.transaction(|tx| tx.apply(&ops))?;
+        self.commit()
+    }
+    fn get_versioned(&self, key: Vec<u8>) -> crate::Result<(Option<Vec<u8>>, u64)> {
+        let (value, version) = self
+            .trees
+            .transaction(|tx| Ok((tx.unexpired(&key)?, tx.version(&key)?)))?;
+        Ok((value.map(|value| value.to_vec()), version))
+    }
+    fn commit_if_unchanged(
+        &self,
+        reads: &BTreeMap<Vec<u8>, u64>,
+        batch: WriteBatch,
+    ) -> crate::Result<bool> {
+        let ops = batch.into_ops();
+        let unchanged = self.trees.transaction(|tx| {
+            for (key, &read) in reads {
+                if tx.version(key)? != read {
+                    return Ok(false);
+                }
+            }
+            tx.apply(&ops)?;
+            Ok(true)
+        })?;
+        if unchanged {
+            self.commit()?;
+        }
+        Ok(unchanged)
+    }
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> crate::Result<bool> {
+        let swapped = self.trees.transaction(|tx| {
+            if tx.unexpired(&key)?.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            match &new {
+                Some(new) => {
+                    tx.insert(&key, new)?;
+                    tx.ttl.remove(key.as_slice())?;
+                }
+                None => {
+                    tx.remove(&key)?;
+                }
+            }
+            Ok(true)
+        })?;
+        if swapped {
+            self.commit()?;
+        }
+        Ok(swapped)
+    }
+    fn set_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<bool> {
+        let present = self.trees.transaction(|tx| {
+            if tx.live_expiry(&key).is_err() {
+                return Ok(false);
+            }
+            tx.insert(&key, &value)?;
+            tx.ttl.remove(key.as_slice())?;
+            Ok(true)
+        })?;
+        if present {
+            self.commit()?;
+        }
+        Ok(present)
+    }
+    fn scan(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> crate::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        self.live_entries(self.trees.db.range(range), limit)
+    }
+    fn scan_prefix(&self, prefix: Vec<u8>) -> crate::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.live_entries(self.trees.db.scan_prefix(prefix), None)
+    }
+    fn increment(&self, key: Vec<u8>, by: i64) -> crate::Result<i64> {
+        let sum = self.trees.transaction(|tx| {
+            let value = tx.live_value(&key)?;
+            let sum = add_to(value.as_deref(), by).map_err(ConflictableTransactionError::Abort)?;
+            tx.insert(&key, sum.to_string().as_bytes())?;
+            Ok(sum)
+        })?;
+        self.commit()?;
+        Ok(sum)
+    }
+    fn append(&self, key: Vec<u8>, suffix: Vec<u8>) -> crate::Result<usize> {
+        let len = self.trees.transaction(|tx| {
+            let mut value = tx.live_value(&key)?.map_or_else(Vec::new, |v| v.to_vec());
+            value.extend_from_slice(&suffix);
+            tx.insert(&key, &value)?;
+            Ok(value.len())
+        })?;
+        self.commit()?;
+        Ok(len)
     }
-    fn remove(&self, key: String) -> crate::Result<()> {
-        let tree = &self.0;
-        tree.remove(key)?.ok_or(KvError::KeyNotFound)?;
-        tree.flush()?;
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> crate::Result<()> {
+        let expires_at = expiry_after(ttl).to_be_bytes();
+        self.trees.transaction(|tx| {
+            tx.insert(&key, &value)?;
+            tx.ttl.insert(key.as_slice(), &expires_at)?;
+            Ok(())
+        })?;
+        self.commit()
+    }
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> crate::Result<()> {
+        let expires_at = expiry_after(ttl).to_be_bytes();
+        self.trees.transaction(|tx| {
+            tx.live_expiry(&key)?;
+            tx.ttl.insert(key.as_slice(), &expires_at)?;
+            tx.touch(&key)
+        })?;
+        self.commit()
+    }
+    fn ttl(&self, key: Vec<u8>) -> crate::Result<Option<Duration>> {
+        let expires_at = self.trees.transaction(|tx| tx.live_expiry(&key))?;
+        Ok(time_left(expires_at))
+    }
+    /// Keys are copied one at a time while writes go on, each together with
+    /// its expiry, rather than the whole database at one instant.
+    fn backup(&self, dest: &Path) -> crate::Result<()> {
+        let target = create_database(dest)?;
+        let target_ttl = target.open_tree("ttl")?;
+        for key in self.trees.db.iter().keys() {
+            let key = key?;
+            let entry = self.trees.transaction(|tx| {
+                let expires_at = expiry(tx.ttl.get(&key)?);
+                Ok(tx.unexpired(&key)?.map(|value| (value, expires_at)))
+            })?;
+            if let Some((value, expires_at)) = entry {
+                target.insert(&key, value)?;
+                if expires_at != 0 {
+                    target_ttl.insert(&key, &expires_at.to_be_bytes())?;
+                }
+            }
+        }
+        target.flush()?;
         Ok(())
     }
 }
 
 impl SledKvsEngine {
+    /// Opens the database in `path`, flushing it after every write.
     pub fn open(path: impl Into<PathBuf>) -> crate::Result<Self> {
-        Ok(SledKvsEngine(sled::open(path.into())?))
+        Self::open_with_sync(path, SyncPolicy::Always)
+    }
+
+    /// Opens the database in `path`, flushing writes to disk according to `sync`.
+    /// [`SyncPolicy::Interval`] and [`SyncPolicy::Never`] use sled's own background
+    /// flushing, the latter at sled's default interval.
+    pub fn open_with_sync(path: impl Into<PathBuf>, sync: SyncPolicy) -> crate::Result<Self> {
+        // without a flusher sled keeps writes in memory until it is dropped,
+        // which a killed server never gets to
+        let flush_every_ms = match sync {
+            SyncPolicy::Interval(interval) => (interval.as_millis() as u64).max(1),
+            SyncPolicy::Never | SyncPolicy::Always | SyncPolicy::GroupCommit => 500,
+        };
+        let db = sled::Config::new()
+            .path(path.into())
+            .flush_every_ms(Some(flush_every_ms))
+            .open()?;
+        let trees = Trees {
+            ttl: db.open_tree("ttl")?,
+            versions: db.open_tree("versions")?,
+            db,
+        };
+        let sweeper = {
+            let trees = trees.clone();
+            Periodic::spawn("sled-sweeper", SWEEP_INTERVAL, move || {
+                if let Err(e) = trees.sweep() {
+                    error!("Sweeping expired keys failed: {}", e);
+                }
+            })?
+        };
+        Ok(SledKvsEngine {
+            trees,
+            sync,
+            group: Arc::default(),
+            _sweeper: Arc::new(sweeper),
+        })
+    }
+
+    /// Rebuilds a database in `path` from a backup made by [`KvsEngine::backup`].
+    /// `path` must not hold a database yet.
+    pub fn restore(backup: &Path, path: &Path) -> crate::Result<()> {
+        if !is_database(backup) {
+            return Err(From::from(format!(
+                "{} holds no database",
+                backup.display()
+            )));
+        }
+        let backup = sled::Config::new().path(backup).open()?;
+        export_into(&backup, path)
+    }
+
+    /// Deletes the database in `path`, which must not be open.
+    pub fn remove_database(path: &Path) -> crate::Result<()> {
+        if !is_database(path) {
+            return Err(From::from(format!("{} holds no database", path.display())));
+        }
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name == "blobs" {
+                fs::remove_dir_all(entry.path())?;
+            } else if name == "conf" || name == "db" || name.starts_with("snap.") {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes a finished write durable if the sync policy asks for it.
+    fn commit(&self) -> crate::Result<()> {
+        match self.sync {
+            SyncPolicy::Always => {
+                self.trees.db.flush()?;
+            }
+            SyncPolicy::GroupCommit => {
+                let ticket = self.group.ticket();
+                self.group.wait(ticket, || {
+                    self.trees.db.flush()?;
+                    Ok(())
+                })?;
+            }
+            SyncPolicy::Interval(_) | SyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+
+    /// Collects up to `limit` entries of `iter` that have not expired.
+    fn live_entries(
+        &self,
+        iter: impl Iterator<Item = sled::Result<(IVec, IVec)>>,
+        limit: Option<usize>,
+    ) -> crate::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let now = now_millis();
+        let mut entries = Vec::new();
+        for entry in iter {
+            if entries.len() >= limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            let (key, value) = entry?;
+            if !is_expired(expiry(self.trees.ttl.get(&key)?), now) {
+                entries.push((key.to_vec(), value.to_vec()));
+            }
+        }
+        Ok(entries)
     }
 }
 
@@ -45,3 +340,159 @@ impl Default for SledKvsEngine {
         .expect("Error creating SledKvStore")
     }
 }
+
+impl Trees {
+    /// Runs `f` as one transaction over all the trees.
+    fn transaction<T>(
+        &self,
+        f: impl Fn(&Tx) -> ConflictableTransactionResult<T, KvError>,
+    ) -> crate::Result<T> {
+        (&*self.db, &self.ttl, &self.versions)
+            .transaction(|(data, ttl, versions)| {
+                f(&Tx {
+                    data,
+                    ttl,
+                    versions,
+                })
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => From::from(e),
+                TransactionError::Storage(e) => From::from(e),
+            })
+    }
+
+    /// Removes every expired key.
+    fn sweep(&self) -> crate::Result<()> {
+        let now = now_millis();
+        for entry in self.ttl.iter() {
+            let (key, expires_at) = entry?;
+            if !is_expired(expiry(Some(expires_at.clone())), now) {
+                continue;
+            }
+            self.transaction(|tx| {
+                // the key may have been set again since
+                if tx.ttl.get(&key)?.as_ref() == Some(&expires_at) {
+                    tx.remove(&key)?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// The trees inside a transaction. Data is written through [`Tx::insert`]
+/// and [`Tx::remove`], which keep the version of the key up to date.
+struct Tx<'a> {
+    data: &'a TransactionalTree,
+    ttl: &'a TransactionalTree,
+    versions: &'a TransactionalTree,
+}
+
+impl Tx<'_> {
+    /// Sets `key` to `value`, leaving its expiry as it is.
+    fn insert(&self, key: &[u8], value: &[u8]) -> ConflictableTransactionResult<(), KvError> {
+        self.data.insert(key, value)?;
+        self.touch(key)
+    }
+
+    /// Removes `key` along with its expiry, returning the value it had.
+    fn remove(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, KvError> {
+        self.ttl.remove(key)?;
+        self.versions.remove(key)?;
+        Ok(self.data.remove(key)?)
+    }
+
+    /// Moves `key` to a new version.
+    fn touch(&self, key: &[u8]) -> ConflictableTransactionResult<(), KvError> {
+        // sled never hands out an id twice; 0 stands for a missing key and 1
+        // for one written before versions were kept
+        let version = self.versions.generate_id()? + 2;
+        self.versions.insert(key, &version.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Version of `key`, see [`KvsEngine::get_versioned`].
+    fn version(&self, key: &[u8]) -> ConflictableTransactionResult<u64, KvError> {
+        if self.unexpired(key)?.is_none() {
+            return Ok(0);
+        }
+        Ok(self
+            .versions
+            .get(key)?
+            .and_then(|bytes| bytes.as_ref().try_into().ok())
+            .map_or(1, u64::from_be_bytes))
+    }
+
+    /// Expiry time of `key`, aborting if it is missing or expired.
+    fn live_expiry(&self, key: &[u8]) -> ConflictableTransactionResult<u64, KvError> {
+        let expires_at = expiry(self.ttl.get(key)?);
+        if self.data.get(key)?.is_none() || is_expired(expires_at, now_millis()) {
+            return Err(ConflictableTransactionError::Abort(KvError::KeyNotFound));
+        }
+        Ok(expires_at)
+    }
+
+    /// Value of `key`, `None` if it is missing or expired.
+    fn unexpired(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, KvError> {
+        if is_expired(expiry(self.ttl.get(key)?), now_millis()) {
+            return Ok(None);
+        }
+        Ok(self.data.get(key)?)
+    }
+
+    /// Like [`Tx::unexpired`], but also clears the expiry of an expired key so
+    /// that it can be written afresh.
+    fn live_value(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, KvError> {
+        let value = self.unexpired(key)?;
+        if value.is_none() {
+            self.ttl.remove(key)?;
+        }
+        Ok(value)
+    }
+
+    /// Applies the operations of a write batch.
+    fn apply(&self, ops: &[BatchOp]) -> ConflictableTransactionResult<(), KvError> {
+        for op in ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    self.insert(key, value)?;
+                    self.ttl.remove(key.as_slice())?;
+                }
+                BatchOp::Remove { key } => {
+                    self.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The expiry time stored for a key, 0 when there is none.
+fn expiry(expires_at: Option<IVec>) -> u64 {
+    expires_at
+        .and_then(|bytes| bytes.as_ref().try_into().ok())
+        .map_or(0, u64::from_be_bytes)
+}
+
+/// Tells whether `path` holds a sled database.
+pub fn is_database(path: &Path) -> bool {
+    // sled's own files, see `sled::Config`
+    path.join("db").exists() || path.join("conf").exists()
+}
+
+/// Creates a new database in `dest`, which must not hold one yet.
+fn create_database(dest: &Path) -> crate::Result<Db> {
+    if is_database(dest) {
+        return Err(From::from(KvError::AlreadyExists(dest.to_path_buf())));
+    }
+    Ok(sled::Config::new().path(dest).open()?)
+}
+
+/// Copies every tree of `db`, expiry times included, into a new database in `dest`.
+fn export_into(db: &Db, dest: &Path) -> crate::Result<()> {
+    let target = create_database(dest)?;
+    target.import(db.export());
+    target.flush()?;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use super::{KvsEngine, WriteBatch};
+use crate::{KvError, Result};
+use std::collections::BTreeMap;
+
+/// Reads and writes over several keys that take effect together, and only if
+/// none of the keys read has changed in the meantime. Started with
+/// [`KvsEngine::begin`].
+///
+/// Writes are buffered until [`Transaction::commit`]; later reads in the
+/// transaction see them.
+#[derive(Debug)]
+pub struct Transaction<E> {
+    engine: E,
+    /// Version of every key read, as of its first read.
+    reads: BTreeMap<Vec<u8>, u64>,
+    /// Pending writes, `None` removing the key.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub fn new(engine: E) -> Self {
+        Transaction {
+            engine,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = self.engine.get_versioned(key.clone())?;
+        self.reads.entry(key).or_insert(version);
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes `key`; unlike [`KvsEngine::remove_bytes`] a missing key is not an error.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    /// Applies the writes atomically, failing with [`KvError::Conflict`]
+    /// if a key read by the transaction has been written since.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        if self.engine.commit_if_unchanged(&self.reads, batch)? {
+            Ok(())
+        } else {
+            Err(From::from(KvError::Conflict))
+        }
+    }
+}
@@ -1,21 +1,70 @@
-use std::{error::Error, fmt::Display};
+use commands::ErrorCode;
+use std::{error::Error, fmt::Display, path::PathBuf};
 pub mod client;
+pub mod codec;
 pub mod commands;
 pub mod engines;
 pub mod server;
 pub mod thread_pool;
 
-pub const MESSAGE_SIZE: usize = 512;
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum KvError {
     KeyNotFound,
+    /// A record failed its checksum or could not be decoded.
+    Corrupt {
+        gen: u64,
+        offset: u64,
+    },
+    /// A data file does not start with the store's magic bytes.
+    UnknownFormat,
+    /// A data file was written in an on-disk format this build cannot read.
+    UnsupportedVersion(u32),
+    /// Another process has the store open.
+    Locked,
+    /// A counter was updated on a value that is not a decimal integer.
+    NotAnInteger,
+    /// A counter update went out of range.
+    Overflow,
+    /// A key read by a transaction was written before it committed.
+    Conflict,
+    /// A message of this many bytes is over [`codec::MAX_FRAME_SIZE`].
+    FrameTooLarge(usize),
+    /// The connection was closed in the middle of a message.
+    TruncatedFrame,
+    /// A backup was to be written where a store already is.
+    AlreadyExists(PathBuf),
+    /// A request failed on the server, for a reason without a variant of its own.
+    Server {
+        code: ErrorCode,
+        message: String,
+    },
 }
 impl Display for KvError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             KvError::KeyNotFound => write!(f, "Key not found"),
+            KvError::Corrupt { gen, offset } => {
+                write!(f, "Corrupt record in segment {} at offset {}", gen, offset)
+            }
+            KvError::UnknownFormat => write!(f, "Not a store data file"),
+            KvError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported data file format version {}", version)
+            }
+            KvError::Locked => write!(f, "Store is locked by another process"),
+            KvError::NotAnInteger => write!(f, "Value is not an integer"),
+            KvError::Overflow => write!(f, "Integer overflow"),
+            KvError::Conflict => write!(f, "Transaction conflict"),
+            KvError::FrameTooLarge(len) => write!(
+                f,
+                "Message of {} bytes exceeds the limit of {} bytes",
+                len,
+                codec::MAX_FRAME_SIZE
+            ),
+            KvError::TruncatedFrame => write!(f, "Connection closed in the middle of a message"),
+            KvError::AlreadyExists(path) => write!(f, "{} already holds a store", path.display()),
+            KvError::Server { message, .. } => write!(f, "{}", message),
         }
     }
 }
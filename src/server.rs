@@ -1,82 +1,531 @@
 use crate::{
-    commands::{KvsCommands, KvsResponse},
-    engines::KvsEngine,
+    codec::{self, Encoding},
+    commands::{
+        ErrorCode, Hello, KvsCommands, KvsResponse, ServerInfo, TxnOp, Welcome, FEATURES,
+        PROTOCOL_VERSION,
+    },
+    engines::{KvsEngine, Transaction},
     thread_pool::ThreadPool,
-    KvError, MESSAGE_SIZE,
+    KvError,
 };
-use log::info;
+use log::{error, info};
 use std::{
-    io::{Read, Write},
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    io::{self, BufReader, BufWriter, Write},
     net::{TcpListener, TcpStream},
-    str,
+    ops::Bound,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+/// How long a transaction may go unused before the server drops it.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a connection may wait for its next request by default.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a new connection waits for a thread before it is turned away.
+const SLOT_WAIT: Duration = Duration::from_millis(500);
+/// How many connections may wait for a thread; more are dropped.
+const WAITING_ROOM: usize = 16;
+/// How long a connection that is turned away may take to send its request.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serves requests over TCP. A connection stays open for any number of
+/// requests, which clients may pipeline, and holds a pool thread until the
+/// client hangs up or it idles out. Connections beyond
+/// [`KvServer::max_connections`] wait a moment for a thread to come free,
+/// then are turned away with [`ErrorCode::ServerBusy`]; one thread does the
+/// waiting, and connections that find it full are dropped.
 pub struct KvServer<E: KvsEngine, T: ThreadPool> {
-    threadpool: T,
+    threadpool: Arc<T>,
     engine: E,
+    sessions: Arc<Sessions<E>>,
+    idle_timeout: Duration,
+    data_dir: PathBuf,
+    slots: Arc<Slots>,
+}
+
+/// What serving a connection takes from its server.
+#[derive(Clone)]
+struct Context<E> {
+    kvs: E,
+    sessions: Arc<Sessions<E>>,
+    data_dir: PathBuf,
+    idle_timeout: Duration,
+}
+
+/// Counts the open connections against the most a server has threads for.
+struct Slots {
+    max: usize,
+    open: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    fn new(max: usize) -> Self {
+        Slots {
+            max,
+            open: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Takes a slot, waiting up to `timeout` for one to be given back.
+    fn take(slots: &Arc<Slots>, timeout: Duration) -> Option<Slot> {
+        let open = slots.open.lock().unwrap();
+        let (mut open, _) = slots
+            .freed
+            .wait_timeout_while(open, timeout, |open| *open >= slots.max)
+            .unwrap();
+        if *open >= slots.max {
+            return None;
+        }
+        *open += 1;
+        Some(Slot(slots.clone()))
+    }
+}
+
+/// A connection a server has a thread for, given back on drop.
+struct Slot(Arc<Slots>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.open.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Transactions begun by clients and not yet committed or aborted. A
+/// transaction spans several requests, so it is kept here under its id.
+struct Sessions<E> {
+    /// Keys the ids, so that one client cannot guess those of another.
+    ids: RandomState,
+    next: AtomicU64,
+    open: Mutex<HashMap<u64, (Transaction<E>, Instant)>>,
+}
+
+impl<E: KvsEngine> Sessions<E> {
+    fn new() -> Self {
+        Sessions {
+            ids: RandomState::new(),
+            next: AtomicU64::new(0),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn begin(&self, kvs: &E) -> u64 {
+        let mut open = self.open();
+        let id = loop {
+            let id = self.ids.hash_one(self.next.fetch_add(1, Ordering::Relaxed));
+            if !open.contains_key(&id) {
+                break id;
+            }
+        };
+        open.insert(id, (kvs.begin(), Instant::now()));
+        id
+    }
+
+    /// Takes transaction `id` out while a request works on it.
+    fn take(&self, id: u64) -> crate::Result<Transaction<E>> {
+        match self.open().remove(&id) {
+            Some((txn, _)) => Ok(txn),
+            None => Err(From::from(KvError::Server {
+                code: ErrorCode::BadRequest,
+                message: format!("Unknown transaction {}", id),
+            })),
+        }
+    }
+
+    /// The open transactions, without those of clients that went away
+    /// without committing or aborting.
+    fn open(&self) -> MutexGuard<'_, HashMap<u64, (Transaction<E>, Instant)>> {
+        let mut open = self.open.lock().unwrap();
+        open.retain(|_, (_, used)| used.elapsed() < TRANSACTION_TIMEOUT);
+        open
+    }
+
+    fn put_back(&self, id: u64, txn: Transaction<E>) {
+        self.open.lock().unwrap().insert(id, (txn, Instant::now()));
+    }
 }
 
 impl<E: KvsEngine, T: ThreadPool> KvServer<E, T> {
     pub fn new(engine: E, pool: T) -> Self {
         Self {
             engine,
-            threadpool: pool,
+            threadpool: Arc::new(pool),
+            sessions: Arc::new(Sessions::new()),
+            idle_timeout: IDLE_TIMEOUT,
+            data_dir: PathBuf::from("."),
+            slots: Arc::new(Slots::new(usize::MAX)),
         }
     }
-    pub fn run(&mut self, addr: &str) -> crate::Result<()> {
-        let listener = TcpListener::bind(&addr)?;
+
+    /// Closes connections that send no request for `timeout`; defaults to 60 seconds.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Directory that backups requested by clients are written under;
+    /// defaults to the working directory.
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = dir.into();
+        self
+    }
+
+    /// Turns away connections while `max` are open; set it to the number of
+    /// pool threads. Unlimited by default.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.slots = Arc::new(Slots::new(max));
+        self
+    }
+
+    pub fn run(&mut self, addr: &str) -> crate::Result<()>
+    where
+        T: Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
         info!("Listening on {}", addr);
+        let context = Context {
+            kvs: self.engine.clone(),
+            sessions: self.sessions.clone(),
+            data_dir: self.data_dir.clone(),
+            idle_timeout: self.idle_timeout,
+        };
+        let waiting = self.open_waiting_room(context.clone())?;
         for stream in listener.incoming() {
             info!("Connection established");
             let stream = stream.unwrap();
-            let kvs = self.engine.clone();
-            self.threadpool.spawn(move || {
-                Self::handle_connection(kvs, stream).unwrap();
-            })
+            match Slots::take(&self.slots, Duration::ZERO) {
+                Some(slot) => Self::serve(&self.threadpool, context.clone(), slot, stream),
+                None => {
+                    if waiting.try_send(stream).is_err() {
+                        info!("Dropping the connection, too many wait for a thread");
+                    }
+                }
+            }
         }
         info!("Connection closed");
         Ok(())
     }
-    fn handle_connection(kvs: E, mut stream: TcpStream) -> crate::Result<()> {
-        let command = Self::get_command(&mut stream)?;
-        info!("Command: {:?}", command);
-        let response = match command {
-            KvsCommands::Get { key } => match kvs.get(key.to_owned())? {
-                Some(val) => KvsResponse::Ok(Some(val)),
-                None => KvsResponse::Err(KvError::KeyNotFound.to_string()),
+
+    /// Starts the thread that connections wait on while every pool thread is
+    /// taken, returning where to send them.
+    fn open_waiting_room(&self, context: Context<E>) -> crate::Result<SyncSender<TcpStream>>
+    where
+        T: Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel::<TcpStream>(WAITING_ROOM);
+        let pool = self.threadpool.clone();
+        let slots = self.slots.clone();
+        thread::Builder::new()
+            .name("kvs-waiting-room".to_owned())
+            .spawn(move || {
+                for stream in receiver {
+                    match Slots::take(&slots, SLOT_WAIT) {
+                        Some(slot) => Self::serve(&pool, context.clone(), slot, stream),
+                        None => {
+                            info!("Turning the connection away, all threads are taken");
+                            if let Err(e) = Self::turn_away(&context.kvs, stream, slots.max) {
+                                error!("Connection failed: {}", e);
+                            }
+                        }
+                    }
+                }
+            })?;
+        Ok(sender)
+    }
+
+    /// Hands `stream` to a pool thread, which gives `slot` back once the
+    /// connection is done.
+    fn serve(pool: &T, context: Context<E>, slot: Slot, stream: TcpStream) {
+        pool.spawn(move || {
+            let _slot = slot;
+            let Context {
+                kvs,
+                sessions,
+                data_dir,
+                idle_timeout,
+            } = context;
+            if let Err(e) = Self::handle_connection(kvs, &sessions, &data_dir, stream, idle_timeout)
+            {
+                error!("Connection failed: {}", e);
+            }
+        })
+    }
+
+    /// Answers the requests on `stream` in order until the client hangs up.
+    fn handle_connection(
+        kvs: E,
+        sessions: &Sessions<E>,
+        data_dir: &Path,
+        stream: TcpStream,
+        idle_timeout: Duration,
+    ) -> crate::Result<()> {
+        stream.set_read_timeout(Some(idle_timeout))?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        let Some(encoding) = Self::handshake(&kvs, &mut reader, &mut writer)? else {
+            return Ok(());
+        };
+        loop {
+            let payload = match codec::read_frame(&mut reader) {
+                Ok(Some(payload)) => payload,
+                Ok(None) => return Ok(()),
+                Err(e) if Self::is_timeout(&*e) => {
+                    info!("Closing idle connection");
+                    return Ok(());
+                }
+                Err(e) => {
+                    // tell the client why before hanging up, if it still listens
+                    let _ = codec::send(&mut writer, encoding, &KvsResponse::error(&*e));
+                    let _ = writer.flush();
+                    return Err(e);
+                }
+            };
+            let response = match encoding.decode(&payload) {
+                Ok(command) => {
+                    info!("Command: {:?}", command);
+                    Self::execute(&kvs, sessions, data_dir, command)
+                }
+                // the frame itself was sound, so the connection can go on
+                Err(e) => KvsResponse::Err {
+                    code: ErrorCode::BadRequest,
+                    message: format!("Unsupported request: {}", e),
+                },
+            };
+            match codec::send(&mut writer, encoding, &response) {
+                // nothing was written yet, so an error can go out in its place
+                Err(e) if matches!(e.downcast_ref(), Some(KvError::FrameTooLarge(_))) => {
+                    codec::send(&mut writer, encoding, &KvsResponse::error(&*e))?
+                }
+                result => result?,
+            }
+            // responses to pipelined requests go out together
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+    }
+
+    /// Answers the first request on `stream` with [`ErrorCode::ServerBusy`],
+    /// then hangs up.
+    fn turn_away(kvs: &E, stream: TcpStream, max: usize) -> crate::Result<()> {
+        stream.set_read_timeout(Some(BUSY_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        let Some(encoding) = Self::handshake(kvs, &mut reader, &mut writer)? else {
+            return Ok(());
+        };
+        if codec::read_frame(&mut reader)?.is_none() {
+            return Ok(());
+        }
+        let busy = KvsResponse::Err {
+            code: ErrorCode::ServerBusy,
+            message: format!("Server busy, all {} connections are taken", max),
+        };
+        codec::send(&mut writer, encoding, &busy)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Greets the client and agrees on the encoding of the rest of the
+    /// connection, `None` if the client hung up first.
+    fn handshake(
+        kvs: &E,
+        reader: &mut impl io::Read,
+        writer: &mut impl Write,
+    ) -> crate::Result<Option<Encoding>> {
+        let reply = match codec::receive::<Hello>(reader, Encoding::Json) {
+            Ok(Some(hello)) => Self::accept(&hello),
+            Ok(None) => return Ok(None),
+            Err(e) => Err(format!("Expected a hello: {}", e)),
+        };
+        let welcome = match &reply {
+            Ok(encoding) => Welcome::Accepted {
+                server: ServerInfo {
+                    protocol_version: PROTOCOL_VERSION,
+                    server_version: env!("CARGO_PKG_VERSION").to_owned(),
+                    engine: kvs.name().to_owned(),
+                    features: FEATURES.iter().map(|&feature| feature.to_owned()).collect(),
+                },
+                encoding: *encoding,
             },
-            KvsCommands::Set { key, value } => match kvs.set(key.clone(), value.clone()) {
+            Err(reason) => Welcome::Rejected(reason.clone()),
+        };
+        codec::send(writer, Encoding::Json, &welcome)?;
+        writer.flush()?;
+        reply.map(Some).map_err(From::from)
+    }
+
+    /// Picks the encoding for a client, or says why it cannot be served.
+    fn accept(hello: &Hello) -> Result<Encoding, String> {
+        if hello.protocol_version != PROTOCOL_VERSION {
+            return Err(format!(
+                "Unsupported protocol version {}, this server speaks {}",
+                hello.protocol_version, PROTOCOL_VERSION
+            ));
+        }
+        info!("Client version {}", hello.client_version);
+        // every encoding is supported, so the client's favourite wins
+        hello
+            .encodings
+            .first()
+            .copied()
+            .ok_or_else(|| "No encoding in common".to_owned())
+    }
+
+    fn is_timeout(e: &(dyn std::error::Error + 'static)) -> bool {
+        e.downcast_ref::<io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        })
+    }
+
+    fn execute(
+        kvs: &E,
+        sessions: &Sessions<E>,
+        data_dir: &Path,
+        command: KvsCommands,
+    ) -> KvsResponse {
+        match command {
+            KvsCommands::Get { key } => match kvs.get_bytes(key) {
+                Ok(Some(val)) => KvsResponse::Ok(Some(val)),
+                Ok(None) => KvsResponse::error(&KvError::KeyNotFound),
+                Err(e) => KvsResponse::error(&*e),
+            },
+            KvsCommands::Set { key, value, ttl_ms } => {
+                let res = match ttl_ms {
+                    Some(ttl_ms) => kvs.set_with_ttl(key, value, Duration::from_millis(ttl_ms)),
+                    None => kvs.set_bytes(key, value),
+                };
+                match res {
+                    Ok(()) => KvsResponse::Ok(None),
+                    Err(e) => KvsResponse::error(&*e),
+                }
+            }
+            KvsCommands::Rm { key } => match kvs.remove_bytes(key) {
                 Ok(()) => KvsResponse::Ok(None),
-                Err(e) => KvsResponse::Err(e.to_string()),
+                Err(e) => KvsResponse::error(&*e),
+            },
+            KvsCommands::Scan { start, end, limit } => {
+                let start = start.map_or(Bound::Unbounded, Bound::Included);
+                let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+                match kvs.scan((start, end), limit) {
+                    Ok(entries) => KvsResponse::Entries(entries),
+                    Err(e) => KvsResponse::error(&*e),
+                }
+            }
+            KvsCommands::ScanPrefix { prefix } => match kvs.scan_prefix(prefix) {
+                Ok(entries) => KvsResponse::Entries(entries),
+                Err(e) => KvsResponse::error(&*e),
             },
-            KvsCommands::Rm { key } => match kvs.remove(key.clone()) {
+            KvsCommands::Expire { key, ttl_ms } => {
+                match kvs.expire(key, Duration::from_millis(ttl_ms)) {
+                    Ok(()) => KvsResponse::Ok(None),
+                    Err(e) => KvsResponse::error(&*e),
+                }
+            }
+            KvsCommands::Ttl { key } => match kvs.ttl(key) {
+                Ok(ttl) => KvsResponse::Ttl(ttl.map(|ttl| ttl.as_millis() as u64)),
+                Err(e) => KvsResponse::error(&*e),
+            },
+            KvsCommands::Batch(batch) => match kvs.write_batch(batch) {
                 Ok(()) => KvsResponse::Ok(None),
-                Err(e) => KvsResponse::Err(e.to_string()),
+                Err(e) => KvsResponse::error(&*e),
             },
-        };
-        stream
-            .write_all(serde_json::to_string(&response).unwrap().as_bytes())
-            .unwrap();
-        stream.flush().unwrap();
-        stream.shutdown(std::net::Shutdown::Both)?;
-        Ok(())
+            KvsCommands::Cas { key, expected, new } => {
+                Self::written(kvs.compare_and_swap(key, expected, new))
+            }
+            KvsCommands::SetIfAbsent { key, value } => Self::written(kvs.set_if_absent(key, value)),
+            KvsCommands::SetIfPresent { key, value } => {
+                Self::written(kvs.set_if_present(key, value))
+            }
+            KvsCommands::Incr { key, by } => Self::integer(kvs.increment(key, by)),
+            KvsCommands::Decr { key, by } => Self::integer(kvs.decrement(key, by)),
+            KvsCommands::Append { key, value } => {
+                Self::integer(kvs.append(key, value).map(|len| len as i64))
+            }
+            KvsCommands::Begin => KvsResponse::Transaction(sessions.begin(kvs)),
+            KvsCommands::Txn { id, op } => match Self::transaction_op(sessions, id, op) {
+                Ok(response) => response,
+                Err(e) => KvsResponse::error(&*e),
+            },
+            KvsCommands::Commit { id } => match sessions.take(id).and_then(|txn| txn.commit()) {
+                Ok(()) => KvsResponse::Ok(None),
+                Err(e) => KvsResponse::error(&*e),
+            },
+            KvsCommands::Abort { id } => match sessions.take(id) {
+                Ok(_) => KvsResponse::Ok(None),
+                Err(e) => KvsResponse::error(&*e),
+            },
+            KvsCommands::Backup { dest } => {
+                match Self::backup_path(data_dir, &dest).and_then(|dest| kvs.backup(&dest)) {
+                    Ok(()) => KvsResponse::Ok(None),
+                    Err(e) => KvsResponse::error(&*e),
+                }
+            }
+        }
     }
 
-    fn get_command(stream: &mut TcpStream) -> crate::Result<KvsCommands> {
-        let mut buffer = vec![];
-        let mut bytes = [0; MESSAGE_SIZE];
-        loop {
-            let bytes_read = stream.read(&mut bytes).unwrap();
-            buffer.extend_from_slice(&bytes);
-            if bytes_read < MESSAGE_SIZE {
-                break;
+    /// Where a backup into `dest` goes, which clients may only name inside
+    /// the data directory.
+    fn backup_path(data_dir: &Path, dest: &Path) -> crate::Result<PathBuf> {
+        let mut components = dest.components().filter(|c| *c != Component::CurDir);
+        let inside = components.clone().next().is_some()
+            && components.all(|c| matches!(c, Component::Normal(_)));
+        if !inside {
+            return Err(From::from(KvError::Server {
+                code: ErrorCode::BadRequest,
+                message: format!(
+                    "Backup destination {} is not a path inside the data directory",
+                    dest.display()
+                ),
+            }));
+        }
+        Ok(data_dir.join(dest))
+    }
+
+    fn transaction_op(sessions: &Sessions<E>, id: u64, op: TxnOp) -> crate::Result<KvsResponse> {
+        let mut txn = sessions.take(id)?;
+        let response = match op {
+            TxnOp::Get { key } => txn.get(key).map(|value| match value {
+                Some(value) => KvsResponse::Ok(Some(value)),
+                None => KvsResponse::error(&KvError::KeyNotFound),
+            }),
+            TxnOp::Set { key, value } => {
+                txn.set(key, value);
+                Ok(KvsResponse::Ok(None))
             }
+            TxnOp::Rm { key } => {
+                txn.remove(key);
+                Ok(KvsResponse::Ok(None))
+            }
+        };
+        sessions.put_back(id, txn);
+        response
+    }
+
+    fn written(res: crate::Result<bool>) -> KvsResponse {
+        match res {
+            Ok(written) => KvsResponse::Written(written),
+            Err(e) => KvsResponse::error(&*e),
         }
+    }
 
-        let content = str::from_utf8(&mut buffer)
-            .unwrap()
-            .trim_matches(char::from(0));
-        let command: KvsCommands = serde_json::from_str(content).unwrap();
-        Ok(command)
+    fn integer(res: crate::Result<i64>) -> KvsResponse {
+        match res {
+            Ok(n) => KvsResponse::Integer(n),
+            Err(e) => KvsResponse::error(&*e),
+        }
     }
 }
@@ -1,11 +1,57 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::path::Path;
+use std::process::{Child, Command};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
+use trash_db::client::KvClient;
+use trash_db::codec::{self, Encoding};
+use trash_db::commands::{
+    ErrorCode, Hello, KvsCommands, KvsResponse, ServerInfo, TxnOp, Welcome, PROTOCOL_VERSION,
+};
+use trash_db::engines::{kvstore::KvStore, sled::SledKvsEngine, KvsEngine};
+use trash_db::server::KvServer;
+use trash_db::thread_pool::{shared_queue::SharedQueueThreadPool, ThreadPool};
+use trash_db::KvError;
+
+/// A `kvs-server` started for a test, killed once dropped.
+struct Server(Child);
+
+impl Server {
+    /// Starts `kvs-server` with `args` in `dir` and gives it a second to listen.
+    fn start(args: &[&str], dir: &Path) -> Server {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Server(child)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let killed = self.0.kill();
+        self.0.wait().expect("failed to wait on server");
+        if !thread::panicking() {
+            killed.expect("server exited before killed");
+        }
+    }
+}
+
+/// Builds `kvs-client` commands against the server at `addr`, run in `dir`.
+fn kvs_client<'a>(addr: &'a str, dir: &'a Path) -> impl Fn(&[&str]) -> Command + 'a {
+    move |args| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(dir);
+        cmd
+    }
+}
 
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
@@ -20,28 +66,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +98,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +137,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +169,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +180,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +191,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +203,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +225,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +245,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +266,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +287,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +295,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +303,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +311,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +319,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +327,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +335,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -303,26 +353,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -340,3 +391,871 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_kvs_engine_options() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::start(
+        &[
+            "--engine",
+            "kvs",
+            "--addr",
+            "127.0.0.1:4006",
+            "--file-name",
+            "data",
+            "--segment-size",
+            "4096",
+            "--sync",
+            "always",
+        ],
+        temp_dir.path(),
+    );
+    let client = kvs_client("127.0.0.1:4006", temp_dir.path());
+
+    client(&["set", "key1", "value1"]).assert().success();
+    drop(server);
+    assert!(temp_dir.path().join("data.1").is_file());
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--compaction-threshold", "1", "--compaction-ratio", "0.5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Writes acknowledged by sled under `--sync never` still reach the disk in
+// the background, before the server is killed.
+#[test]
+fn cli_sled_sync_never() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::start(
+        &[
+            "--engine",
+            "sled",
+            "--addr",
+            "127.0.0.1:4023",
+            "--sync",
+            "never",
+        ],
+        temp_dir.path(),
+    );
+    let client = kvs_client("127.0.0.1:4023", temp_dir.path());
+
+    client(&["set", "key1", "value1"]).assert().success();
+    thread::sleep(Duration::from_secs(2));
+    drop(server);
+    let engine = SledKvsEngine::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4007"], temp_dir.path());
+    let client = kvs_client("127.0.0.1:4007", temp_dir.path());
+
+    for (key, value) in [("key2", "value2"), ("key1", "value1"), ("other", "x")] {
+        client(&["set", key, value]).assert().success();
+    }
+    client(&["scan", "range", "key", "--limit", "2"])
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\nkey2\tvalue2\n");
+    client(&["scan", "prefix", "oth"])
+        .assert()
+        .success()
+        .stdout("other\tx\n");
+}
+
+#[test]
+fn cli_expiry() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4008"], temp_dir.path());
+    let client = kvs_client("127.0.0.1:4008", temp_dir.path());
+
+    client(&["set", "key1", "value1", "--ttl", "100"])
+        .assert()
+        .success();
+    client(&["ttl", "key1"]).assert().success().stdout("100\n");
+    client(&["set", "key2", "value2"]).assert().success();
+    client(&["ttl", "key2"]).assert().success().stdout("none\n");
+    client(&["expire", "key2", "1"]).assert().success();
+    client(&["ttl", "key2"]).assert().success().stdout("1\n");
+    thread::sleep(Duration::from_millis(1100));
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["expire", "key2", "1"])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    // too many seconds to count in milliseconds, which is as good as forever
+    client(&["set", "key3", "value3", "--ttl", "18446744073709552"])
+        .assert()
+        .success();
+    client(&["expire", "key3", "18446744073709552"])
+        .assert()
+        .success();
+    client(&["get", "key3"])
+        .assert()
+        .success()
+        .stdout("value3\n");
+}
+
+#[test]
+fn cli_batch() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4009"], temp_dir.path());
+    let client = kvs_client("127.0.0.1:4009", temp_dir.path());
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&[
+        "batch", "set", "key2", "value2", "rm", "key1", "set", "key3", "value3",
+    ])
+    .assert()
+    .success();
+    client(&["scan", "range"])
+        .assert()
+        .success()
+        .stdout("key2\tvalue2\nkey3\tvalue3\n");
+    client(&["batch", "set", "key4"])
+        .assert()
+        .failure()
+        .stderr(contains("Missing operand"));
+}
+
+#[test]
+fn cli_conditional_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4010"], temp_dir.path());
+    let client = kvs_client("127.0.0.1:4010", temp_dir.path());
+
+    client(&["set-if-present", "key1", "value1"])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+    client(&["set-if-absent", "key1", "value1"])
+        .assert()
+        .success();
+    client(&["set-if-absent", "key1", "value2"])
+        .assert()
+        .failure()
+        .stderr(contains("Key already exists"));
+    client(&["cas", "key1", "--expected", "value2", "--new", "value3"])
+        .assert()
+        .failure()
+        .stderr(contains("Value did not match"));
+    client(&["cas", "key1", "--expected", "value1", "--new", "value3"])
+        .assert()
+        .success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value3\n");
+    client(&["cas", "key1", "--expected", "value3"])
+        .assert()
+        .success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+}
+
+#[test]
+fn cli_counters() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4011"], temp_dir.path());
+    let client = kvs_client("127.0.0.1:4011", temp_dir.path());
+
+    client(&["incr", "hits"]).assert().success().stdout("1\n");
+    client(&["incr", "hits", "--by", "10"])
+        .assert()
+        .success()
+        .stdout("11\n");
+    client(&["decr", "hits", "--by", "-2"])
+        .assert()
+        .success()
+        .stdout("13\n");
+    client(&["append", "hits", "0"])
+        .assert()
+        .success()
+        .stdout("3\n");
+    client(&["get", "hits"]).assert().success().stdout("130\n");
+    client(&["set", "name", "value"]).assert().success();
+    client(&["incr", "name"])
+        .assert()
+        .failure()
+        .stderr(contains("Value is not an integer"));
+}
+
+#[test]
+fn cli_transactions() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4012"], temp_dir.path());
+    let client = kvs_client("127.0.0.1:4012", temp_dir.path());
+
+    let begin = || {
+        let output = client(&["begin"]).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    };
+
+    client(&["set", "key1", "value1"]).assert().success();
+    let id = begin();
+    client(&["get", "key1", "--txn", &id])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["set", "key2", "value2", "--txn", &id])
+        .assert()
+        .success();
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["commit", &id]).assert().success();
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    // ids do not follow one another, so other clients cannot guess them
+    let first: u64 = id.parse().unwrap();
+    let id = begin();
+    assert_ne!(id.parse::<u64>().unwrap(), first.wrapping_add(1));
+    client(&["get", "key1", "--txn", &id]).assert().success();
+    client(&["rm", "key2", "--txn", &id]).assert().success();
+    client(&["set", "key1", "value3"]).assert().success();
+    client(&["commit", &id])
+        .assert()
+        .failure()
+        .stderr(contains("Transaction conflict"));
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    client(&["abort", &id])
+        .assert()
+        .failure()
+        .stderr(contains(format!("Unknown transaction {}", id)));
+}
+
+#[test]
+fn cli_backup_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let data = temp_dir.path().join("data");
+    let restored = temp_dir.path().join("restored");
+    fs::create_dir(&data).unwrap();
+    fs::create_dir(&restored).unwrap();
+    let server = Server::start(&["--addr", "127.0.0.1:4013"], &data);
+    let client = kvs_client("127.0.0.1:4013", temp_dir.path());
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["backup", "backup"]).assert().success();
+    client(&["backup", "./backup"])
+        .assert()
+        .failure()
+        .stderr(contains("already holds a store"));
+    // backups stay inside the data directory of the server
+    let outside = temp_dir.path().join("outside");
+    for dest in [
+        "../outside",
+        outside.to_str().unwrap(),
+        "backup/../../outside",
+    ] {
+        client(&["backup", dest])
+            .assert()
+            .failure()
+            .stderr(contains("not a path inside the data directory"));
+    }
+    assert!(!outside.exists());
+    drop(server);
+
+    // the backup is only read
+    let files = |dir: &std::path::Path| {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    };
+    let backup = data.join("backup");
+    let before = files(&backup);
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["restore", "../data/backup"])
+        .current_dir(&restored)
+        .assert()
+        .success();
+    assert_eq!(files(&backup), before);
+    let store = KvStore::open(&restored).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn cli_restore_detects_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let backup = temp_dir.path().join("backup");
+    let empty = temp_dir.path().join("empty");
+    let restored = temp_dir.path().join("restored");
+    fs::create_dir(&empty).unwrap();
+    fs::create_dir(&restored).unwrap();
+    let engine = SledKvsEngine::open(temp_dir.path().join("sled")).unwrap();
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    engine.backup(&backup).unwrap();
+    drop(engine);
+    let restore = |from: &str, args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(args)
+            .args(["restore", from])
+            .current_dir(&restored);
+        cmd
+    };
+
+    restore("../empty", &[])
+        .assert()
+        .failure()
+        .stderr(contains("holds no store"));
+    assert_eq!(fs::read_dir(&empty).unwrap().count(), 0);
+    restore("../backup", &["--engine", "kvs"])
+        .assert()
+        .failure()
+        .stderr(contains("holds a Sled backup"));
+    assert_eq!(fs::read_dir(&restored).unwrap().count(), 0);
+    restore("../backup", &[]).assert().success();
+    assert_eq!(
+        fs::read_to_string(restored.join(".engine")).unwrap(),
+        "\"Sled\""
+    );
+    let engine = SledKvsEngine::open(&restored).unwrap();
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    let client = kvs_client("127.0.0.1:4014", temp_dir.path());
+
+    let running = Server::start(&["--addr", "127.0.0.1:4014"], temp_dir.path());
+    client(&["set", "key1", "value1"]).assert().success();
+    drop(running);
+
+    server(&["migrate", "--to", "kvs"])
+        .assert()
+        .failure()
+        .stderr(contains("Already using"));
+    server(&["migrate", "--to", "sled", "--remove-old"])
+        .assert()
+        .success();
+    assert!(!temp_dir.path().join(".store.1").exists());
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join(".engine")).unwrap(),
+        "\"Sled\""
+    );
+
+    let running = Server::start(
+        &["--addr", "127.0.0.1:4014", "--engine", "sled"],
+        temp_dir.path(),
+    );
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    drop(running);
+
+    // the kvs files are gone, so the data can move back; the sled files stay
+    server(&["migrate", "--to", "kvs"]).assert().success();
+    server(&["migrate", "--to", "sled"])
+        .assert()
+        .failure()
+        .stderr(contains("already holds data"));
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn cli_export_import() {
+    let temp_dir = TempDir::new().unwrap();
+    let (data, loaded) = (temp_dir.path().join("data"), temp_dir.path().join("loaded"));
+    fs::create_dir(&data).unwrap();
+    fs::create_dir(&loaded).unwrap();
+    let store = KvStore::open(&data).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set_bytes(b"key2".to_vec(), vec![0xff]).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["export"])
+        .current_dir(&data)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"key1","value":"value1"}"#))
+        .stdout(contains(r#""encoding":"base64""#));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["export", "../dump.csv", "--format", "csv"])
+        .current_dir(&data)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["import", "../dump.csv", "--format", "csv"])
+        .current_dir(&loaded)
+        .assert()
+        .success();
+    let store = KvStore::open(&loaded).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_bytes(b"key2".to_vec()).unwrap(), Some(vec![0xff]));
+}
+
+#[test]
+fn cli_admin() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let stats = store.stats().unwrap();
+    drop(store);
+    let admin = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    // a directory without a store is left alone
+    let empty = TempDir::new().unwrap();
+    for command in ["stats", "compact", "verify"] {
+        admin(&[command, "--dir", empty.path().to_str().unwrap()])
+            .assert()
+            .failure()
+            .stderr(contains("holds no store"));
+    }
+    assert_eq!(fs::read_dir(empty.path()).unwrap().count(), 0);
+
+    admin(&["dump"])
+        .assert()
+        .success()
+        .stdout(contains("1\t8\t"))
+        .stdout(contains("rm\tkey1\t-\t0"));
+    // worked out offline, as the store would account for it once opened
+    admin(&["stats"]).assert().success().stdout(format!(
+        "keys\t{}\nsegments\t{}\nlive_bytes\t{}\nstale_bytes\t{}\n",
+        stats.keys, stats.segments, stats.live_bytes, stats.stale_bytes
+    ));
+    admin(&["verify"])
+        .assert()
+        .success()
+        .stdout(contains("4 records OK"));
+
+    let segment = temp_dir.path().join(".store.1");
+    let mut content = fs::read(&segment).unwrap();
+    content.push(0xff);
+    content.extend_from_slice(&[0; 20]);
+    fs::write(&segment, content).unwrap();
+    admin(&["verify"])
+        .assert()
+        .failure()
+        .stderr(contains("Segment 1 is unreadable"));
+    admin(&["repair"])
+        .assert()
+        .success()
+        .stdout(contains("Cut 21 bytes from 1 segments"));
+    admin(&["verify"]).assert().success();
+
+    admin(&["compact"])
+        .assert()
+        .success()
+        .stdout(contains("stale_bytes\t0"));
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}
+
+// Messages that fill the old 512 byte read buffer exactly or span several
+// reads come through intact.
+#[test]
+fn cli_large_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4015"], temp_dir.path());
+    let client = kvs_client("127.0.0.1:4015", temp_dir.path());
+
+    for len in [100, 512, 4096, 100_000] {
+        let value = "v".repeat(len);
+        client(&["set", "key1", &value]).assert().success();
+        client(&["get", "key1"])
+            .assert()
+            .success()
+            .stdout(format!("{}\n", value));
+    }
+}
+
+// One connection serves many requests, pipelined ones answered in order, and
+// is closed by the server once it has been idle for too long.
+#[test]
+fn cli_pipelining() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(
+        &["--addr", "127.0.0.1:4016", "--idle-timeout", "1"],
+        temp_dir.path(),
+    );
+
+    let mut client = KvClient::connect("127.0.0.1:4016").unwrap();
+    let mut commands = Vec::new();
+    for i in 0..500 {
+        commands.push(KvsCommands::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+            ttl_ms: None,
+        });
+        commands.push(KvsCommands::Get {
+            key: format!("key{}", i).into_bytes(),
+        });
+    }
+    let responses = client.pipeline(&commands).unwrap();
+    assert_eq!(responses.len(), 1000);
+    for (i, pair) in responses.chunks(2).enumerate() {
+        assert!(matches!(pair[0], KvsResponse::Ok(None)));
+        match &pair[1] {
+            KvsResponse::Ok(Some(value)) => assert_eq!(value, format!("value{}", i).as_bytes()),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+    for _ in 0..3 {
+        let response = client
+            .request(&KvsCommands::Get {
+                key: b"key7".to_vec(),
+            })
+            .unwrap();
+        assert!(matches!(response, KvsResponse::Ok(Some(_))));
+    }
+
+    thread::sleep(Duration::from_millis(1500));
+    let get = KvsCommands::Get {
+        key: b"key7".to_vec(),
+    };
+    assert!(client.request(&get).is_err());
+}
+
+// Each connection picks its encoding; a client that opens with anything but
+// a hello is turned away.
+#[test]
+fn cli_encodings() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4017"], temp_dir.path());
+    let client = kvs_client("127.0.0.1:4017", temp_dir.path());
+
+    client(&["set", "key1", "value1", "--json"])
+        .assert()
+        .success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["get", "key1", "--json"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    for encoding in [Encoding::Json, Encoding::Bincode] {
+        let client = KvClient::connect_with_encoding("127.0.0.1:4017", encoding).unwrap();
+        assert_eq!(client.encoding(), encoding);
+    }
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4017").unwrap();
+    codec::send(&mut stream, Encoding::Json, &Hello::new(vec![])).unwrap();
+    match codec::receive(&mut stream, Encoding::Json).unwrap() {
+        Some(Welcome::Rejected(_)) => {}
+        other => panic!("unexpected welcome {:?}", other),
+    }
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4017").unwrap();
+    let get = KvsCommands::Get {
+        key: b"key1".to_vec(),
+    };
+    codec::send(&mut stream, Encoding::Json, &get).unwrap();
+    match codec::receive(&mut stream, Encoding::Json).unwrap() {
+        Some(Welcome::Rejected(_)) => {}
+        other => panic!("unexpected welcome {:?}", other),
+    }
+}
+
+// The hello exchange tells the client about the server, and peers that speak
+// another protocol version are turned away on either side.
+#[test]
+fn cli_handshake() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::start(
+        &["--addr", "127.0.0.1:4018", "--engine", "sled"],
+        temp_dir.path(),
+    );
+
+    kvs_client("127.0.0.1:4018", temp_dir.path())(&["info"])
+        .assert()
+        .success()
+        .stdout(contains(format!("protocol_version\t{}", PROTOCOL_VERSION)))
+        .stdout(contains("engine\tsled"))
+        .stdout(contains("transactions"));
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4018").unwrap();
+    let mut hello = Hello::new(vec![Encoding::Bincode]);
+    hello.protocol_version = PROTOCOL_VERSION + 1;
+    codec::send(&mut stream, Encoding::Json, &hello).unwrap();
+    match codec::receive(&mut stream, Encoding::Json).unwrap() {
+        Some(Welcome::Rejected(reason)) => assert!(reason.contains("protocol version")),
+        other => panic!("unexpected welcome {:?}", other),
+    }
+
+    // a request the server cannot decode is answered, and the connection stays usable
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4018").unwrap();
+    codec::send(
+        &mut stream,
+        Encoding::Json,
+        &Hello::new(vec![Encoding::Bincode]),
+    )
+    .unwrap();
+    let _: Option<Welcome> = codec::receive(&mut stream, Encoding::Json).unwrap();
+    codec::write_frame(&mut stream, &[0xff, 0xff]).unwrap();
+    match codec::receive(&mut stream, Encoding::Bincode).unwrap() {
+        Some(KvsResponse::Err { code, message }) => {
+            assert_eq!(code, ErrorCode::BadRequest);
+            assert!(message.contains("Unsupported request"));
+        }
+        other => panic!("unexpected response {:?}", other),
+    }
+    let get = KvsCommands::Get {
+        key: b"key1".to_vec(),
+    };
+    codec::send(&mut stream, Encoding::Bincode, &get).unwrap();
+    assert!(matches!(
+        codec::receive(&mut stream, Encoding::Bincode).unwrap(),
+        Some(KvsResponse::Err {
+            code: ErrorCode::NotFound,
+            ..
+        })
+    ));
+    drop(server);
+
+    // a server from the future
+    let listener = std::net::TcpListener::bind("127.0.0.1:4019").unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _: Option<Hello> = codec::receive(&mut stream, Encoding::Json).unwrap();
+        let welcome = Welcome::Accepted {
+            server: ServerInfo {
+                protocol_version: PROTOCOL_VERSION + 1,
+                server_version: "99.0.0".to_owned(),
+                engine: "kvs".to_owned(),
+                features: vec![],
+            },
+            encoding: Encoding::Bincode,
+        };
+        codec::send(&mut stream, Encoding::Json, &welcome).unwrap();
+    });
+    let err = KvClient::connect("127.0.0.1:4019").err().unwrap();
+    assert!(err.to_string().contains("protocol version"));
+    server.join().unwrap();
+}
+
+// Failures carry a code on the wire and come back to clients as the matching
+// `KvError`; only a missing key is an answer to `kvs-client get`.
+#[test]
+fn cli_error_codes() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4020"], temp_dir.path());
+
+    let mut client = KvClient::connect("127.0.0.1:4020").unwrap();
+    let get = |key: &[u8]| KvsCommands::Get { key: key.to_vec() };
+    let err = client.request(&get(b"key1")).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<KvError>(),
+        Some(KvError::KeyNotFound)
+    ));
+
+    let set = KvsCommands::Set {
+        key: b"key1".to_vec(),
+        value: b"value1".to_vec(),
+        ttl_ms: None,
+    };
+    client.request(&set).unwrap();
+    let incr = KvsCommands::Incr {
+        key: b"key1".to_vec(),
+        by: 1,
+    };
+    match client.request(&incr).unwrap_err().downcast_ref::<KvError>() {
+        Some(KvError::Server { code, message }) => {
+            assert_eq!(*code, ErrorCode::BadRequest);
+            assert_eq!(message, "Value is not an integer");
+        }
+        other => panic!("unexpected error {:?}", other),
+    }
+    let commit = KvsCommands::Commit { id: 42 };
+    match client
+        .request(&commit)
+        .unwrap_err()
+        .downcast_ref::<KvError>()
+    {
+        Some(KvError::Server { code, .. }) => assert_eq!(*code, ErrorCode::BadRequest),
+        other => panic!("unexpected error {:?}", other),
+    }
+
+    // a transaction that read key1 before someone else wrote it
+    let Ok(KvsResponse::Transaction(id)) = client.request(&KvsCommands::Begin) else {
+        panic!("no transaction begun");
+    };
+    let read = KvsCommands::Txn {
+        id,
+        op: TxnOp::Get {
+            key: b"key1".to_vec(),
+        },
+    };
+    client.request(&read).unwrap();
+    client.request(&set).unwrap();
+    let err = client.request(&KvsCommands::Commit { id }).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<KvError>(),
+        Some(KvError::Conflict)
+    ));
+
+    // pipelined failures stay responses, next to the ones that worked
+    let responses = client.pipeline(&[get(b"key2"), get(b"key1")]).unwrap();
+    assert!(matches!(
+        responses[0],
+        KvsResponse::Err {
+            code: ErrorCode::NotFound,
+            ..
+        }
+    ));
+    assert!(matches!(responses[1], KvsResponse::Ok(Some(_))));
+
+    // a backup into one that is already there is the client's mistake
+    let backup = KvsCommands::Backup {
+        dest: "backup".into(),
+    };
+    client.request(&backup).unwrap();
+    match client
+        .request(&backup)
+        .unwrap_err()
+        .downcast_ref::<KvError>()
+    {
+        Some(KvError::Server { code, message }) => {
+            assert_eq!(*code, ErrorCode::BadRequest);
+            assert!(message.contains("already holds a store"));
+        }
+        other => panic!("unexpected error {:?}", other),
+    }
+
+    // the open connection would hold a pool thread until it idles out
+    drop(client);
+
+    let client = kvs_client("127.0.0.1:4020", temp_dir.path());
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["get", "key1", "--txn", "42"])
+        .assert()
+        .failure()
+        .stderr(contains("Unknown transaction 42"));
+    client(&["incr", "key1"])
+        .assert()
+        .failure()
+        .stderr(contains("Value is not an integer"));
+}
+
+// A response over the frame limit is answered with an error, and the
+// connection goes on.
+#[test]
+fn cli_response_too_large() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..5u8 {
+        store.set_bytes(vec![i], vec![i; 14 * 1024 * 1024]).unwrap();
+    }
+    drop(store);
+    fs::write(temp_dir.path().join(".engine"), "\"Kvs\"").unwrap();
+    let _server = Server::start(&["--addr", "127.0.0.1:4021"], temp_dir.path());
+
+    let mut client = KvClient::connect("127.0.0.1:4021").unwrap();
+    let scan = KvsCommands::Scan {
+        start: None,
+        end: None,
+        limit: None,
+    };
+    match client.request(&scan).unwrap_err().downcast_ref::<KvError>() {
+        Some(KvError::Server { code, .. }) => assert_eq!(*code, ErrorCode::TooLarge),
+        other => panic!("unexpected error {:?}", other),
+    }
+    let scan = KvsCommands::Scan {
+        start: None,
+        end: None,
+        limit: Some(1),
+    };
+    assert!(matches!(
+        client.request(&scan).unwrap(),
+        KvsResponse::Entries(entries) if entries.len() == 1
+    ));
+}
+
+// Connections beyond the threads of the server are turned away as busy
+// rather than left waiting.
+#[test]
+fn cli_server_busy() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    thread::spawn(move || {
+        KvServer::new(store, pool)
+            .max_connections(1)
+            .run("127.0.0.1:4022")
+            .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let get = KvsCommands::Get {
+        key: b"key1".to_vec(),
+    };
+    let mut first = KvClient::connect("127.0.0.1:4022").unwrap();
+    assert!(first
+        .request(&get)
+        .is_err_and(|e| matches!(e.downcast_ref::<KvError>(), Some(KvError::KeyNotFound))));
+    let mut second = KvClient::connect("127.0.0.1:4022").unwrap();
+    match second.request(&get).unwrap_err().downcast_ref::<KvError>() {
+        Some(KvError::Server { code, .. }) => assert_eq!(*code, ErrorCode::ServerBusy),
+        other => panic!("unexpected error {:?}", other),
+    }
+
+    // a thread is free again once the first client hangs up
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let mut third = KvClient::connect("127.0.0.1:4022").unwrap();
+    assert!(third
+        .request(&get)
+        .is_err_and(|e| matches!(e.downcast_ref::<KvError>(), Some(KvError::KeyNotFound))));
+}
@@ -0,0 +1,102 @@
+use std::io::{self, Read};
+use trash_db::codec::{read_frame, receive, send, write_frame, Encoding, MAX_FRAME_SIZE};
+use trash_db::commands::{KvsCommands, KvsResponse};
+use trash_db::{KvError, Result};
+
+/// Hands out the bytes it holds a few at a time, like a slow connection.
+struct Trickle<'a> {
+    bytes: &'a [u8],
+    step: usize,
+}
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.step.min(buf.len()).min(self.bytes.len());
+        buf[..n].copy_from_slice(&self.bytes[..n]);
+        self.bytes = &self.bytes[n..];
+        Ok(n)
+    }
+}
+
+// Frames come through whole however the reads are split, back to back and
+// whatever their size.
+#[test]
+fn frames_survive_short_reads() -> Result<()> {
+    let mut wire = Vec::new();
+    for len in [0, 1, 512, 1024, 100_000] {
+        write_frame(&mut wire, &vec![b'x'; len])?;
+    }
+    send(
+        &mut wire,
+        Encoding::Json,
+        &KvsCommands::Get {
+            key: b"key1".to_vec(),
+        },
+    )?;
+
+    let mut reader = Trickle {
+        bytes: &wire,
+        step: 3,
+    };
+    for len in [0, 1, 512, 1024, 100_000] {
+        assert_eq!(read_frame(&mut reader)?, Some(vec![b'x'; len]));
+    }
+    match receive(&mut reader, Encoding::Json)? {
+        Some(KvsCommands::Get { key }) => assert_eq!(key, b"key1"),
+        other => panic!("unexpected message {:?}", other),
+    }
+    assert_eq!(read_frame(&mut reader)?, None);
+    Ok(())
+}
+
+#[test]
+fn truncated_and_oversized_frames() -> Result<()> {
+    let mut wire = Vec::new();
+    write_frame(&mut wire, b"value1")?;
+    for cut in [2, wire.len() - 1] {
+        let err = read_frame(&mut &wire[..cut]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvError>(),
+            Some(KvError::TruncatedFrame)
+        ));
+    }
+
+    let prefix = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+    let err = read_frame(&mut &prefix[..]).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<KvError>(),
+        Some(KvError::FrameTooLarge(_))
+    ));
+    let err = write_frame(&mut Vec::new(), &vec![0; MAX_FRAME_SIZE + 1]).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<KvError>(),
+        Some(KvError::FrameTooLarge(_))
+    ));
+    Ok(())
+}
+
+// Both encodings carry arbitrary bytes; bincode does it at about their size.
+#[test]
+fn encodings_round_trip() -> Result<()> {
+    let value: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+    let response = KvsResponse::Entries(vec![(vec![0, 0xff], value.clone())]);
+    for encoding in [Encoding::Json, Encoding::Bincode] {
+        let payload = encoding.encode(&response)?;
+        match encoding.decode(&payload)? {
+            KvsResponse::Entries(entries) => {
+                assert_eq!(entries, vec![(vec![0, 0xff], value.clone())])
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+        if encoding == Encoding::Bincode {
+            assert!(payload.len() < value.len() + 16);
+        }
+    }
+
+    // a length inside the message larger than the frame could carry
+    let mut payload = Encoding::Bincode.encode(&KvsResponse::Ok(Some(vec![1, 2, 3])))?;
+    payload.truncate(2);
+    payload.extend_from_slice(&[0xfd, 0xff, 0xff, 0xff, 0xff]);
+    assert!(Encoding::Bincode.decode::<KvsResponse>(&payload).is_err());
+    Ok(())
+}